@@ -1,6 +1,9 @@
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     tonic_build::configure()
         .build_server(false)
+        // Answered as is by the webhook endpoints.
+        .type_attribute("rinha.WebhookSubscription", "#[derive(serde::Serialize)]")
+        .type_attribute("rinha.WebhookDelivery", "#[derive(serde::Serialize)]")
         .compile(&["proto/rinha.proto"], &["proto"])?;
     Ok(())
 }
@@ -1 +1,33 @@
+use actix_web::HttpResponse;
+
 pub mod pessoa;
+pub mod webhook;
+
+/// The intermediary API sheds the new pessoas when its insert queue is full,
+/// which is told to the client as a 503 so it retries later. The admin RPCs
+/// refuse invalid or missing subscriptions, and webhooks being disabled. A read
+/// times out at the database, or when it was sent with a consistency token whose
+/// pessoa takes too long to commit.
+fn error_response(status: &tonic::Status) -> HttpResponse {
+    match status.code() {
+        tonic::Code::ResourceExhausted => {
+            let retry_after = status
+                .metadata()
+                .get("retry-after")
+                .and_then(|retry_after| retry_after.to_str().ok())
+                .unwrap_or("1");
+            HttpResponse::ServiceUnavailable()
+                .append_header(("Retry-After", retry_after))
+                .finish()
+        }
+        tonic::Code::InvalidArgument => {
+            HttpResponse::BadRequest().body(status.message().to_owned())
+        }
+        tonic::Code::NotFound => HttpResponse::NotFound().finish(),
+        tonic::Code::DeadlineExceeded => HttpResponse::GatewayTimeout().finish(),
+        tonic::Code::FailedPrecondition => {
+            HttpResponse::Conflict().body(status.message().to_owned())
+        }
+        _ => HttpResponse::InternalServerError().finish(),
+    }
+}
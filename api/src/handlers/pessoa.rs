@@ -1,11 +1,25 @@
+use super::error_response;
 use crate::{
     models::pessoa::PessoaInput,
-    rinha::{CountPessoaRequest, PessoaByIdRequest, PessoaSearchRequest},
+    rinha::{
+        CountConsistency, CountPessoaRequest, PessoaByIdRequest, PessoaSearchRequest, SearchMode,
+    },
     utils::app_state::AppState,
 };
-use actix_web::{http::StatusCode, web, HttpResponse, Responder};
+use actix_web::{http::StatusCode, web, HttpRequest, HttpResponse, Responder};
 use serde::Deserialize;
 
+/// Returned by `POST /pessoas` and sent back at the reads that must find the created pessoa.
+const CONSISTENCY_TOKEN_HEADER: &str = "X-Consistency-Token";
+
+fn consistency_token(request: &HttpRequest) -> Option<String> {
+    request
+        .headers()
+        .get(CONSISTENCY_TOKEN_HEADER)
+        .and_then(|token| token.to_str().ok())
+        .map(str::to_owned)
+}
+
 #[actix_web::post("/pessoas")]
 pub async fn create(
     input: web::Json<PessoaInput>,
@@ -17,11 +31,13 @@ pub async fn create(
         .create_pessoa(tonic::Request::new(input.into_inner().into()))
         .await
         .map(tonic::Response::into_inner)
-        .ok()
     {
-        Some(res) => {
+        Ok(res) => {
             let mut response =
                 HttpResponse::build(StatusCode::from_u16(res.status as u16).unwrap());
+            if let Some(token) = res.consistency_token {
+                response.append_header((CONSISTENCY_TOKEN_HEADER, token));
+            }
             if let Some(id) = res.id {
                 let location = format!("/pessoas/{}", id);
                 response.append_header(("Location", location)).finish()
@@ -29,17 +45,22 @@ pub async fn create(
                 response.finish()
             }
         }
-        None => HttpResponse::InternalServerError().finish(),
+        Err(status) => error_response(&status),
     }
 }
 
 #[actix_web::get("/pessoas/{id}")]
-pub async fn get(id: web::Path<String>, app_state: web::Data<AppState>) -> impl Responder {
+pub async fn get(
+    id: web::Path<String>,
+    request: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
     match app_state
         .rinha_client
         .clone()
         .pessoa_by_id(tonic::Request::new(PessoaByIdRequest {
             id: id.into_inner(),
+            consistency_token: consistency_token(&request),
         }))
         .await
     {
@@ -49,47 +70,114 @@ pub async fn get(id: web::Path<String>, app_state: web::Data<AppState>) -> impl
                 .body(json),
             None => HttpResponse::NotFound().finish(),
         },
-        Err(_) => HttpResponse::InternalServerError().finish(),
+        Err(status) => error_response(&status),
     }
 }
 
 #[derive(Deserialize)]
 pub struct SearchInput {
-    t: String,
+    t: Option<String>,
+    /// Only the pessoas having exactly this stack.
+    stack: Option<String>,
+    /// Only the pessoas created since this RFC 3339 date time or `%Y-%m-%d` date.
+    desde: Option<String>,
+    /// `similarity` ranks the pessoas by the similarity of their data to `t`.
+    mode: Option<String>,
+    /// Minimum similarity of the `similarity` mode, between 0 and 1.
+    threshold: Option<f32>,
+    /// Adds the similarity `score` to the pessoas of the `similarity` mode.
+    #[serde(default)]
+    score: bool,
+}
+
+/// Parses `desde` into a unix timestamp in milliseconds.
+fn parse_desde(desde: &str) -> Option<i64> {
+    match chrono::DateTime::parse_from_rfc3339(desde) {
+        Ok(desde) => Some(desde.timestamp_millis()),
+        Err(_) => chrono::NaiveDate::parse_from_str(desde, "%Y-%m-%d")
+            .ok()?
+            .and_hms_opt(0, 0, 0)
+            .map(|desde| desde.timestamp_millis()),
+    }
 }
 
 #[actix_web::get("/pessoas")]
-pub async fn all(input: web::Query<SearchInput>, app_state: web::Data<AppState>) -> impl Responder {
+pub async fn all(
+    input: web::Query<SearchInput>,
+    request: HttpRequest,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let SearchInput {
+        t,
+        stack,
+        desde,
+        mode,
+        threshold,
+        score,
+    } = input.into_inner();
+    if t.is_none() && stack.is_none() && desde.is_none() {
+        return HttpResponse::BadRequest().finish();
+    }
+    let desde = match desde.as_deref().map(parse_desde) {
+        Some(None) => return HttpResponse::BadRequest().finish(),
+        desde => desde.flatten(),
+    };
+    let mode = match mode.as_deref() {
+        None | Some("substring") => SearchMode::Substring,
+        Some("similarity") => SearchMode::Similarity,
+        Some(_) => return HttpResponse::BadRequest().finish(),
+    };
     match app_state
         .rinha_client
         .clone()
         .pessoa_search(tonic::Request::new(PessoaSearchRequest {
-            term: input.into_inner().t,
+            term: t.unwrap_or_default(),
+            stack,
+            desde,
+            mode: mode.into(),
+            similarity_threshold: threshold,
+            include_score: score,
+            consistency_token: consistency_token(&request),
         }))
         .await
-        .ok()
         .map(|res| res.into_inner().json)
-        .flatten()
     {
-        Some(pessoas) => HttpResponse::Ok()
+        Ok(Some(pessoas)) => HttpResponse::Ok()
             .append_header(actix_web::http::header::ContentType::json())
             .body(pessoas),
-        None => HttpResponse::InternalServerError().finish(),
+        Ok(None) => HttpResponse::InternalServerError().finish(),
+        Err(status) => error_response(&status),
     }
 }
 
+#[derive(Deserialize)]
+pub struct CountInput {
+    /// `exact` waits for the queued pessoas to be inserted and counts them at the database,
+    /// `fast` or none answers a counter that includes the queued pessoas, anything else is a 400.
+    consistency: Option<String>,
+}
+
 #[actix_web::get("/contagem-pessoas")]
-pub async fn count(app_state: web::Data<AppState>) -> impl Responder {
+pub async fn count(
+    input: web::Query<CountInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    let consistency = match input.into_inner().consistency.as_deref() {
+        None | Some("fast") => CountConsistency::Fast,
+        Some("exact") => CountConsistency::Exact,
+        Some(_) => return HttpResponse::BadRequest().finish(),
+    };
     match app_state
         .rinha_client
         .clone()
-        .count_pessoa(tonic::Request::new(CountPessoaRequest {}))
+        .count_pessoa(tonic::Request::new(CountPessoaRequest {
+            consistency: consistency.into(),
+        }))
         .await
-        .ok()
         .map(|res| res.into_inner().amount)
     {
-        Some(amount) => HttpResponse::Ok().json(amount),
-        _ => HttpResponse::InternalServerError().finish(),
+        Ok(amount) => HttpResponse::Ok().json(amount),
+        Err(status) => error_response(&status),
     }
 }
 
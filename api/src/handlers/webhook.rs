@@ -0,0 +1,155 @@
+use super::error_response;
+use crate::{
+    models::webhook::WebhookInput,
+    rinha::{
+        ListWebhookDeliveriesRequest, ListWebhookSubscriptionsRequest, WebhookSubscriptionRequest,
+    },
+    utils::app_state::AppState,
+};
+use actix_web::{
+    dev::Payload, error::ErrorUnauthorized, http::header, web, FromRequest, HttpRequest,
+    HttpResponse, Responder,
+};
+use serde::Deserialize;
+use std::future::{ready, Ready};
+
+/// A request carrying the `Authorization: Bearer {ADMIN_TOKEN}` header, the
+/// webhook routes make the server post to any URL, so they are not public.
+pub struct Admin;
+
+impl FromRequest for Admin {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(request: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let expected = request
+            .app_data::<web::Data<AppState>>()
+            .and_then(|app_state| app_state.admin_token.as_deref());
+        let given = request
+            .headers()
+            .get(header::AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+        ready(match (expected, given) {
+            (Some(expected), Some(given)) if constant_time_eq(expected, given) => Ok(Admin),
+            _ => Err(ErrorUnauthorized("Missing or invalid admin token")),
+        })
+    }
+}
+
+/// Compares without bailing out at the first different byte, which would tell how much of the token was guessed.
+fn constant_time_eq(a: &str, b: &str) -> bool {
+    a.len() == b.len()
+        && a.bytes()
+            .zip(b.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+/// Subscribes an URL to the `pessoa.created` events, the answered secret is not shown again.
+#[actix_web::post("/webhooks")]
+pub async fn create(
+    _: Admin,
+    input: web::Json<WebhookInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    match app_state
+        .rinha_admin_client
+        .clone()
+        .create_webhook_subscription(tonic::Request::new(input.into_inner().into()))
+        .await
+        .map(tonic::Response::into_inner)
+    {
+        Ok(subscription) => HttpResponse::Created()
+            .append_header(("Location", format!("/webhooks/{}", subscription.id)))
+            .json(subscription),
+        Err(status) => error_response(&status),
+    }
+}
+
+#[actix_web::get("/webhooks")]
+pub async fn all(_: Admin, app_state: web::Data<AppState>) -> impl Responder {
+    match app_state
+        .rinha_admin_client
+        .clone()
+        .list_webhook_subscriptions(tonic::Request::new(ListWebhookSubscriptionsRequest {}))
+        .await
+    {
+        Ok(res) => HttpResponse::Ok().json(res.into_inner().subscriptions),
+        Err(status) => error_response(&status),
+    }
+}
+
+/// Enables a subscription disabled after failing too many deliveries.
+#[actix_web::post("/webhooks/{id}/enable")]
+pub async fn enable(
+    _: Admin,
+    id: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    match app_state
+        .rinha_admin_client
+        .clone()
+        .enable_webhook_subscription(tonic::Request::new(WebhookSubscriptionRequest {
+            id: id.into_inner(),
+        }))
+        .await
+    {
+        Ok(res) => HttpResponse::Ok().json(res.into_inner()),
+        Err(status) => error_response(&status),
+    }
+}
+
+#[actix_web::delete("/webhooks/{id}")]
+pub async fn delete(
+    _: Admin,
+    id: web::Path<String>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    match app_state
+        .rinha_admin_client
+        .clone()
+        .delete_webhook_subscription(tonic::Request::new(WebhookSubscriptionRequest {
+            id: id.into_inner(),
+        }))
+        .await
+    {
+        Ok(_) => HttpResponse::NoContent().finish(),
+        Err(status) => error_response(&status),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DeliveriesInput {
+    /// Amount of the most recent deliveries, up to 100.
+    limit: Option<u32>,
+}
+
+#[actix_web::get("/webhooks/{id}/deliveries")]
+pub async fn deliveries(
+    _: Admin,
+    id: web::Path<String>,
+    input: web::Query<DeliveriesInput>,
+    app_state: web::Data<AppState>,
+) -> impl Responder {
+    match app_state
+        .rinha_admin_client
+        .clone()
+        .list_webhook_deliveries(tonic::Request::new(ListWebhookDeliveriesRequest {
+            subscription_id: id.into_inner(),
+            limit: input.into_inner().limit,
+        }))
+        .await
+    {
+        Ok(res) => HttpResponse::Ok().json(res.into_inner().deliveries),
+        Err(status) => error_response(&status),
+    }
+}
+
+pub fn config(cfg: &mut web::ServiceConfig) {
+    cfg.service(create)
+        .service(all)
+        .service(enable)
+        .service(delete)
+        .service(deliveries);
+}
@@ -2,7 +2,7 @@ mod handlers;
 mod models;
 mod utils;
 
-use crate::handlers::pessoa;
+use crate::handlers::{pessoa, webhook};
 use crate::utils::app_state::AppState;
 use crate::utils::env::{EnvironmentValues, LoggerOutput};
 use crate::utils::telemetry;
@@ -28,14 +28,23 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let socket: SocketAddr = format!("[::]:{}", env_values.server_port).parse()?;
     tracing::info!("Starting App Server at: {}", socket);
     let app_state = web::Data::new(app_state);
+    let admin = env_values.admin_token.is_some();
+    // Actix stops accepting connections on SIGTERM and waits for the requests
+    // in flight up to the shutdown timeout, before `run` returns.
     if env_values.logger.is_none() {
         HttpServer::new(move || {
             App::new()
                 .app_data(app_state.clone())
                 .wrap(Cors::permissive())
                 .configure(pessoa::config)
+                .configure(|cfg| {
+                    if admin {
+                        webhook::config(cfg)
+                    }
+                })
         })
         .keep_alive(Duration::from_secs(200))
+        .shutdown_timeout(env_values.shutdown_timeout_secs)
         .bind(&socket)?
         .run()
         .await?;
@@ -46,8 +55,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
                 .wrap(Cors::permissive())
                 .wrap(TracingLogger::default())
                 .configure(pessoa::config)
+                .configure(|cfg| {
+                    if admin {
+                        webhook::config(cfg)
+                    }
+                })
         })
         .keep_alive(Duration::from_secs(200))
+        .shutdown_timeout(env_values.shutdown_timeout_secs)
         .bind(&socket)?
         .run()
         .await?;
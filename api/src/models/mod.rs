@@ -1 +1,2 @@
 pub mod pessoa;
+pub mod webhook;
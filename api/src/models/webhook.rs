@@ -0,0 +1,14 @@
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct WebhookInput {
+    pub url: String,
+    /// Key of the `X-Rinha-Signature` HMAC, generated when not given.
+    pub secret: Option<String>,
+}
+
+impl From<WebhookInput> for crate::rinha::CreateWebhookSubscriptionRequest {
+    fn from(WebhookInput { url, secret }: WebhookInput) -> Self {
+        Self { url, secret }
+    }
+}
@@ -1,7 +1,7 @@
 use std::time::Duration;
 
 use super::env::EnvironmentValues;
-use crate::rinha::rinha_client::RinhaClient;
+use crate::rinha::{rinha_admin_client::RinhaAdminClient, rinha_client::RinhaClient};
 use tonic::transport::Channel;
 use tonic_tracing_opentelemetry::middleware::client::OtelGrpcService;
 use tower::ServiceBuilder;
@@ -9,6 +9,8 @@ use tower::ServiceBuilder;
 #[derive(Clone)]
 pub struct AppState {
     pub rinha_client: RinhaClient<OtelGrpcService<tonic::transport::Channel>>,
+    pub rinha_admin_client: RinhaAdminClient<OtelGrpcService<tonic::transport::Channel>>,
+    pub admin_token: Option<String>,
 }
 
 impl AppState {
@@ -42,10 +44,12 @@ impl AppState {
             }
         };
         let channel = ServiceBuilder::new()
-            .layer(tonic_tracing_opentelemetry::middleware::client::OtelGrpcLayer::default())
+            .layer(tonic_tracing_opentelemetry::middleware::client::OtelGrpcLayer)
             .service(channel);
         Ok(Self {
-            rinha_client: RinhaClient::new(channel),
+            rinha_client: RinhaClient::new(channel.clone()),
+            rinha_admin_client: RinhaAdminClient::new(channel),
+            admin_token: env_values.admin_token.clone(),
         })
     }
 }
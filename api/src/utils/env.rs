@@ -2,12 +2,18 @@ use dotenv::dotenv;
 use std::{env, str::FromStr};
 
 pub struct EnvironmentValues {
+    #[allow(dead_code)]
     pub redis_url: String,
+    #[allow(dead_code)]
     pub database_url: String,
     pub server_port: u16,
+    #[allow(dead_code)]
     pub rust_env: String,
     pub logger: Option<LoggerOutput>,
     pub rinha_url: String,
+    pub shutdown_timeout_secs: u64,
+    /// Bearer token of the webhook routes, which are not served without one.
+    pub admin_token: Option<String>,
 }
 
 pub enum LoggerOutput {
@@ -40,11 +46,17 @@ impl EnvironmentValues {
             rust_env: env::var("RUST_ENV").unwrap_or_else(|_| "dev".into()),
             logger: std::env::var("LOGGER_OUTPUT")
                 .ok()
-                .map(|s| s.parse().ok())
-                .flatten(),
+                .and_then(|s| s.parse().ok()),
             rinha_url: std::env::var("RINHA_URL")
                 .ok()
                 .unwrap_or(String::from("http://[::]:50051")),
+            shutdown_timeout_secs: env::var("SHUTDOWN_TIMEOUT_SECS")
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .unwrap_or(8),
+            admin_token: env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
         }
     }
 }
@@ -0,0 +1,2 @@
+-- The primary key is on APELIDO, not on ID.
+ALTER TABLE PESSOAS RENAME CONSTRAINT ID_PK TO PESSOAS_APELIDO_PK;
@@ -0,0 +1,21 @@
+-- Stacks were joined with spaces, so an entry like "Ruby on Rails" can't be told apart
+-- from three entries anymore, the existing ones are split at the spaces.
+ALTER TABLE PESSOAS DROP COLUMN BUSCA_TRGM;
+ALTER TABLE PESSOAS ALTER COLUMN STACK TYPE TEXT[] USING CASE
+    WHEN STACK = '' THEN '{}'
+    ELSE STRING_TO_ARRAY(STACK, ' ')
+END;
+
+-- `ARRAY_TO_STRING` is only stable as it handles any array, while it is immutable for a
+-- `TEXT[]`, as required by a generated column. A pessoa without stack is now searchable.
+CREATE FUNCTION PESSOA_BUSCA(NOME TEXT, APELIDO TEXT, STACK TEXT[]) RETURNS TEXT
+    LANGUAGE SQL IMMUTABLE PARALLEL SAFE
+    RETURN LOWER(NOME || APELIDO || COALESCE(ARRAY_TO_STRING(STACK, ' '), ''));
+
+ALTER TABLE PESSOAS ADD COLUMN BUSCA_TRGM TEXT GENERATED ALWAYS AS (
+    PESSOA_BUSCA(NOME, APELIDO, STACK)
+) STORED;
+
+CREATE INDEX IDX_PESSOAS_BUSCA_TRGM ON PESSOAS USING GIST (BUSCA_TRGM GIST_TRGM_OPS(SIGLEN=64));
+-- Answers `STACK @> ARRAY[...]`, the pessoas having every given stack.
+CREATE INDEX IDX_PESSOAS_STACK ON PESSOAS USING GIN (STACK);
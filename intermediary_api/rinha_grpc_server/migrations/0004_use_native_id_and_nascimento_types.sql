@@ -0,0 +1,3 @@
+ALTER TABLE PESSOAS
+    ALTER COLUMN ID TYPE UUID USING ID::UUID,
+    ALTER COLUMN NASCIMENTO TYPE DATE USING NASCIMENTO::DATE;
@@ -0,0 +1,9 @@
+-- Ids are UUIDv7 since this version, so their index is ordered by creation time as well.
+CREATE INDEX IDX_PESSOAS_ID ON PESSOAS (ID);
+
+-- The creation time held by the first 48 bits of a UUIDv7, NULL for the UUIDv4 of older pessoas.
+CREATE FUNCTION PESSOA_CREATED_AT(ID UUID) RETURNS TIMESTAMPTZ
+    LANGUAGE SQL IMMUTABLE PARALLEL SAFE
+    RETURN CASE WHEN SUBSTR(ID::TEXT, 15, 1) = '7' THEN
+        TO_TIMESTAMP(('x' || SUBSTR(REPLACE(ID::TEXT, '-', ''), 1, 12))::BIT(48)::BIGINT / 1000.0)
+    END;
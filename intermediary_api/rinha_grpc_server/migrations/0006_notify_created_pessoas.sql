@@ -0,0 +1,21 @@
+-- Tells every instance listening on PESSOAS_CREATED about the committed pessoas, so they keep their caches
+-- coherent. A notification payload is limited to 8000 bytes, the pessoas not fitting it are sent by id only.
+CREATE FUNCTION NOTIFY_PESSOAS_CREATED() RETURNS TRIGGER
+    LANGUAGE PLPGSQL
+AS $$
+BEGIN
+    PERFORM PG_NOTIFY('pessoas_created', CASE WHEN OCTET_LENGTH(PAYLOAD) < 8000 THEN PAYLOAD
+        ELSE JSON_BUILD_OBJECT('id', ID)::TEXT END)
+    FROM (
+        SELECT ID, JSON_BUILD_OBJECT('id', ID, 'apelido', APELIDO, 'nome', NOME, 'nascimento', NASCIMENTO,
+            'stack', STACK)::TEXT AS PAYLOAD
+        FROM CREATED
+    ) C;
+    RETURN NULL;
+END;
+$$;
+
+-- Only the rows actually inserted are at the transition table, the ones skipped by ON CONFLICT are not.
+CREATE TRIGGER PESSOAS_CREATED AFTER INSERT ON PESSOAS
+    REFERENCING NEW TABLE AS CREATED
+    FOR EACH STATEMENT EXECUTE FUNCTION NOTIFY_PESSOAS_CREATED();
@@ -0,0 +1,21 @@
+-- Transactional outbox of the pessoa lifecycle events, written by the same statement inserting the pessoas and relayed
+-- to an event sink.
+CREATE TABLE PESSOA_EVENTS (
+    SEQ BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    TXID XID8 NOT NULL DEFAULT PG_CURRENT_XACT_ID(),
+    KIND TEXT NOT NULL,
+    PAYLOAD JSONB NOT NULL,
+    CREATED_AT TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Sequence numbers are taken before committing, so a transaction may commit events with lower ones than those of
+-- a transaction that committed earlier. The relay only reads the events of finished transactions, ordered by
+-- transaction, any one still running or to be started has a greater TXID than them.
+CREATE INDEX IDX_PESSOA_EVENTS_TXID_SEQ ON PESSOA_EVENTS (TXID, SEQ);
+
+-- The last event delivered to each sink.
+CREATE TABLE OUTBOX_CURSORS (
+    SINK TEXT PRIMARY KEY,
+    TXID XID8 NOT NULL DEFAULT '0',
+    SEQ BIGINT NOT NULL DEFAULT 0
+);
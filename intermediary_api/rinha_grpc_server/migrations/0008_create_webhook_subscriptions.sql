@@ -0,0 +1,26 @@
+-- Callback URLs the pessoa events are posted to, each one relayed from the outbox with its own cursor.
+CREATE TABLE WEBHOOK_SUBSCRIPTIONS (
+    ID UUID PRIMARY KEY,
+    URL TEXT NOT NULL,
+    SECRET TEXT NOT NULL,
+    ENABLED BOOLEAN NOT NULL DEFAULT TRUE,
+    CONSECUTIVE_FAILURES INTEGER NOT NULL DEFAULT 0,
+    -- Failed deliveries are retried from then on.
+    NEXT_ATTEMPT_AT TIMESTAMPTZ NOT NULL DEFAULT NOW(),
+    CREATED_AT TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+-- Every delivery attempt, successful ones have no ERROR.
+CREATE TABLE WEBHOOK_DELIVERIES (
+    ID BIGINT GENERATED ALWAYS AS IDENTITY PRIMARY KEY,
+    SUBSCRIPTION_ID UUID NOT NULL REFERENCES WEBHOOK_SUBSCRIPTIONS ON DELETE CASCADE,
+    FIRST_SEQ BIGINT NOT NULL,
+    LAST_SEQ BIGINT NOT NULL,
+    EVENTS INTEGER NOT NULL,
+    STATUS_CODE INTEGER,
+    ERROR TEXT,
+    DURATION_MS BIGINT NOT NULL,
+    DELIVERED_AT TIMESTAMPTZ NOT NULL DEFAULT NOW()
+);
+
+CREATE INDEX IDX_WEBHOOK_DELIVERIES_SUBSCRIPTION ON WEBHOOK_DELIVERIES (SUBSCRIPTION_ID, ID DESC);
@@ -0,0 +1,19 @@
+-- Building and sending the notifications is wasted when no instance listens to them, so only the pessoas inserted by the
+-- sessions setting RINHA.CACHE_COHERENCY, which the instances do when CACHE_COHERENCY is enabled, are notified.
+CREATE OR REPLACE FUNCTION NOTIFY_PESSOAS_CREATED() RETURNS TRIGGER
+    LANGUAGE PLPGSQL
+AS $$
+BEGIN
+    IF COALESCE(CURRENT_SETTING('rinha.cache_coherency', TRUE), '') <> 'on' THEN
+        RETURN NULL;
+    END IF;
+    PERFORM PG_NOTIFY('pessoas_created', CASE WHEN OCTET_LENGTH(PAYLOAD) < 8000 THEN PAYLOAD
+        ELSE JSON_BUILD_OBJECT('id', ID)::TEXT END)
+    FROM (
+        SELECT ID, JSON_BUILD_OBJECT('id', ID, 'apelido', APELIDO, 'nome', NOME, 'nascimento', NASCIMENTO,
+            'stack', STACK)::TEXT AS PAYLOAD
+        FROM CREATED
+    ) C;
+    RETURN NULL;
+END;
+$$;
@@ -0,0 +1,4 @@
+-- The relays lease the cursors instead of locking them through the deliveries, which kept a transaction open meanwhile.
+ALTER TABLE OUTBOX_CURSORS
+    ADD COLUMN LEASED_BY UUID,
+    ADD COLUMN LEASED_UNTIL TIMESTAMPTZ NOT NULL DEFAULT '-infinity';
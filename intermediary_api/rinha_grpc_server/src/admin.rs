@@ -0,0 +1,194 @@
+use std::sync::Arc;
+
+use tonic::{Request, Response, Status};
+use uuid::Uuid;
+
+use crate::{
+    batch::insert_pessoas,
+    rinha::{
+        rinha_admin_server::RinhaAdmin, CreateWebhookSubscriptionRequest,
+        DeleteWebhookSubscriptionReply, ListWebhookDeliveriesReply, ListWebhookDeliveriesRequest,
+        ListWebhookSubscriptionsReply, ListWebhookSubscriptionsRequest, ReplayDeadLettersReply,
+        ReplayDeadLettersRequest, SetPoolLimitReply, SetPoolLimitRequest, WebhookSubscription,
+        WebhookSubscriptionRequest,
+    },
+    utils::env::EnvironmentValues,
+    webhooks,
+    with_cache::MyRinha,
+};
+
+/// Answered by default by `ListWebhookDeliveries`, which is also its maximum.
+const WEBHOOK_DELIVERIES_LIMIT: u32 = 100;
+
+fn internal(err: sqlx::Error) -> Status {
+    Status::internal(err.to_string())
+}
+
+fn invalid_id(_: uuid::Error) -> Status {
+    Status::invalid_argument("Invalid subscription id")
+}
+
+/// Subscriptions would never be delivered to without the events being written.
+fn webhooks_disabled() -> Status {
+    Status::failed_precondition("Webhooks are disabled")
+}
+
+/// Operational RPCs. The `api` only exposes the webhook ones, and only to the
+/// requests carrying its `ADMIN_TOKEN`.
+pub struct MyRinhaAdmin {
+    pub rinha: Arc<MyRinha>,
+    pub env_values: Arc<EnvironmentValues>,
+}
+
+#[tonic::async_trait]
+impl RinhaAdmin for MyRinhaAdmin {
+    async fn replay_dead_letters(
+        &self,
+        _: Request<ReplayDeadLettersRequest>,
+    ) -> Result<Response<ReplayDeadLettersReply>, Status> {
+        let (replayed, outcome) = self
+            .rinha
+            .dead_letters
+            .replay(|pessoas| async {
+                let outcome =
+                    insert_pessoas(&self.rinha, pessoas, &self.env_values.batch_retry).await;
+                self.rinha.settle_replayed(&outcome);
+                let settled = outcome.settled;
+                (outcome, settled)
+            })
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        self.rinha
+            .pessoa_count
+            .settled(0, outcome.inserted.len() as u64);
+        tracing::info!(
+            message = "Replayed dead letters.",
+            replayed,
+            inserted = outcome.inserted.len(),
+            dead_lettered = outcome.dead_lettered.len()
+        );
+        // Those inserted are skipped as conflicting by the next replay.
+        if !outcome.settled {
+            return Err(Status::internal(
+                "Failed to store the dead letters failing again, they are kept for the next replay",
+            ));
+        }
+        Ok(Response::new(ReplayDeadLettersReply {
+            replayed: replayed as u64,
+            dead_lettered: outcome.dead_lettered.len() as u64,
+        }))
+    }
+
+    async fn create_webhook_subscription(
+        &self,
+        request: Request<CreateWebhookSubscriptionRequest>,
+    ) -> Result<Response<WebhookSubscription>, Status> {
+        if self.env_values.webhooks.is_none() {
+            return Err(webhooks_disabled());
+        }
+        let CreateWebhookSubscriptionRequest { url, secret } = request.into_inner();
+        let parsed = reqwest::Url::parse(&url)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+            .ok_or_else(|| Status::invalid_argument("The url must be an absolute http(s) URL"))?;
+        let allow_private_targets = self
+            .env_values
+            .webhooks
+            .as_ref()
+            .is_some_and(|config| config.allow_private_targets);
+        if !allow_private_targets {
+            webhooks::check_target(&parsed)
+                .await
+                .map_err(Status::invalid_argument)?;
+        }
+        if secret.as_ref().is_some_and(String::is_empty) {
+            return Err(Status::invalid_argument("The secret must not be empty"));
+        }
+        let subscription = webhooks::create_subscription(&self.rinha.db, url, secret)
+            .await
+            .map_err(internal)?;
+        tracing::info!(
+            message = "Created a webhook subscription.",
+            id = subscription.id,
+            url = subscription.url
+        );
+        Ok(Response::new(subscription))
+    }
+
+    async fn list_webhook_subscriptions(
+        &self,
+        _: Request<ListWebhookSubscriptionsRequest>,
+    ) -> Result<Response<ListWebhookSubscriptionsReply>, Status> {
+        let subscriptions = webhooks::list_subscriptions(&self.rinha.db)
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(ListWebhookSubscriptionsReply {
+            subscriptions,
+        }))
+    }
+
+    async fn enable_webhook_subscription(
+        &self,
+        request: Request<WebhookSubscriptionRequest>,
+    ) -> Result<Response<WebhookSubscription>, Status> {
+        if self.env_values.webhooks.is_none() {
+            return Err(webhooks_disabled());
+        }
+        let id = Uuid::parse_str(&request.get_ref().id).map_err(invalid_id)?;
+        match webhooks::enable_subscription(&self.rinha.db, id)
+            .await
+            .map_err(internal)?
+        {
+            Some(subscription) => Ok(Response::new(subscription)),
+            None => Err(Status::not_found("Subscription not found")),
+        }
+    }
+
+    async fn delete_webhook_subscription(
+        &self,
+        request: Request<WebhookSubscriptionRequest>,
+    ) -> Result<Response<DeleteWebhookSubscriptionReply>, Status> {
+        let id = Uuid::parse_str(&request.get_ref().id).map_err(invalid_id)?;
+        if webhooks::delete_subscription(&self.rinha.db, id)
+            .await
+            .map_err(internal)?
+        {
+            Ok(Response::new(DeleteWebhookSubscriptionReply {}))
+        } else {
+            Err(Status::not_found("Subscription not found"))
+        }
+    }
+
+    async fn list_webhook_deliveries(
+        &self,
+        request: Request<ListWebhookDeliveriesRequest>,
+    ) -> Result<Response<ListWebhookDeliveriesReply>, Status> {
+        let request = request.into_inner();
+        let id = Uuid::parse_str(&request.subscription_id).map_err(invalid_id)?;
+        let limit = request
+            .limit
+            .unwrap_or(WEBHOOK_DELIVERIES_LIMIT)
+            .min(WEBHOOK_DELIVERIES_LIMIT);
+        let deliveries = webhooks::list_deliveries(&self.rinha.db, id, limit.into())
+            .await
+            .map_err(internal)?;
+        Ok(Response::new(ListWebhookDeliveriesReply { deliveries }))
+    }
+
+    async fn set_pool_limit(
+        &self,
+        request: Request<SetPoolLimitRequest>,
+    ) -> Result<Response<SetPoolLimitReply>, Status> {
+        let limit = request.into_inner().limit;
+        let previous_limit = self
+            .rinha
+            .pool_limit
+            .set_limit(limit)
+            .map_err(Status::invalid_argument)?;
+        tracing::info!(message = "Set the pool limit.", previous_limit, limit);
+        Ok(Response::new(SetPoolLimitReply {
+            previous_limit,
+            limit,
+        }))
+    }
+}
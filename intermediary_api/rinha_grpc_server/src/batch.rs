@@ -0,0 +1,592 @@
+use chrono::NaiveDate;
+use opentelemetry::metrics::{Counter, ObservableGauge};
+use sqlx::{Connection, PgConnection};
+use tokio::{
+    select,
+    sync::{mpsc, oneshot},
+};
+
+use crate::{
+    models::pessoa::Pessoa,
+    pool::{NamedPool, PoolLimit},
+    utils::env::{BatchInsertMethod, BatchRetryConfig, EnvironmentValues},
+    wal::SegmentId,
+    with_cache::MyRinha,
+};
+use std::{
+    collections::{BTreeMap, HashSet},
+    sync::Arc,
+};
+use tokio::time::Instant;
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// The receiving halves of the channels consumed by the [`batch_insert_task`].
+pub struct BatchQueue {
+    pub pessoas: mpsc::Receiver<QueuedPessoa>,
+    /// Requests to insert every pessoa queued so far, answered once they are committed.
+    pub flushes: mpsc::UnboundedReceiver<oneshot::Sender<()>>,
+}
+
+/// A pessoa waiting at the channel to be inserted by the [`batch_insert_task`].
+pub struct QueuedPessoa {
+    pub pessoa: Pessoa,
+    /// The write ahead log segment to release once the pessoa is committed.
+    pub wal_segment: Option<SegmentId>,
+    /// Settled at the consistency tokens once the pessoa is committed.
+    pub seq: u64,
+}
+
+pub struct BatchMetrics {
+    retries: Counter<u64>,
+    dead_letters: Counter<u64>,
+    shed: Counter<u64>,
+    _queue_depth: ObservableGauge<u64>,
+}
+
+impl BatchMetrics {
+    pub fn new(pessoa_sender: &mpsc::Sender<QueuedPessoa>) -> Self {
+        let meter = opentelemetry::global::meter("rinha_grpc_server");
+        // A weak sender, so the channel still closes once the server drops its own.
+        let pessoa_sender = pessoa_sender.downgrade();
+        Self {
+            shed: meter
+                .u64_counter("batch_insert.shed")
+                .with_description("Pessoas refused due to the insert queue being full")
+                .init(),
+            _queue_depth: meter
+                .u64_observable_gauge("batch_insert.queue_depth")
+                .with_description("Pessoas waiting at the insert queue")
+                .with_callback(move |gauge| {
+                    if let Some(sender) = pessoa_sender.upgrade() {
+                        gauge.observe((sender.max_capacity() - sender.capacity()) as u64, &[]);
+                    }
+                })
+                .init(),
+            retries: meter
+                .u64_counter("batch_insert.retries")
+                .with_description("Insert attempts retried after a transient error")
+                .init(),
+            dead_letters: meter
+                .u64_counter("batch_insert.dead_letters")
+                .with_description("Pessoas that could not be inserted and were dead lettered")
+                .init(),
+        }
+    }
+
+    pub fn shed(&self) {
+        self.shed.add(1, &[]);
+    }
+}
+
+/// Completes a `WITH inserted AS (INSERT INTO pessoas ...` so the `pessoa.created` events of the
+/// inserted pessoas are written to the outbox, within the same statement and so the same transaction.
+/// Returns the ids of the inserted pessoas, like [`RETURNING_IDS`].
+const INSERT_CREATED_EVENTS: &str = " RETURNING id, apelido, nome, nascimento, stack), events AS (INSERT INTO pessoa_events (kind, payload) SELECT 'pessoa.created', json_build_object('id', id, 'apelido', apelido, 'nome', nome, 'nascimento', nascimento, 'stack', stack) FROM inserted) SELECT id FROM inserted;";
+/// Completes an `INSERT INTO pessoas ...` so it returns the ids of the inserted pessoas.
+const RETURNING_IDS: &str = " RETURNING id;";
+
+fn insert_query<'a>(
+    pessoas: impl IntoIterator<Item = &'a Pessoa>,
+    write_events: bool,
+) -> sqlx::QueryBuilder<'a, sqlx::Postgres> {
+    let mut query = sqlx::QueryBuilder::<sqlx::Postgres>::new(if write_events {
+        "WITH inserted AS ("
+    } else {
+        ""
+    });
+    query.push("INSERT INTO pessoas (id, nome, apelido, nascimento, stack) ");
+    query.push_values(pessoas, |mut b, pessoa| {
+        b.push_bind(pessoa.id)
+            .push_bind(&pessoa.nome)
+            .push_bind(&pessoa.apelido)
+            .push_bind(pessoa.nascimento)
+            .push_bind(&pessoa.stack);
+    });
+    query.push(" ON CONFLICT DO NOTHING");
+    query.push(if write_events {
+        INSERT_CREATED_EVENTS
+    } else {
+        RETURNING_IDS
+    });
+    query
+}
+
+/// Session scoped table the batches are copied into, its rows are dropped at
+/// the end of each transaction.
+const CREATE_STAGING_TABLE: &str =
+    "CREATE TEMPORARY TABLE IF NOT EXISTS pessoas_staging (LIKE pessoas) ON COMMIT DELETE ROWS;";
+const COPY_INTO_STAGING_TABLE: &str =
+    "COPY pessoas_staging (id, nome, apelido, nascimento, stack) FROM STDIN (FORMAT binary);";
+/// `COPY` has no `ON CONFLICT`, so the copied rows are moved with an `INSERT` that has it.
+const INSERT_FROM_STAGING_TABLE: &str = "INSERT INTO pessoas (id, nome, apelido, nascimento, stack) SELECT id, nome, apelido, nascimento, stack FROM pessoas_staging ON CONFLICT DO NOTHING";
+
+/// Oid of the `text` type, the element type of the `stack` array.
+const TEXT_OID: i32 = 25;
+
+/// Day zero of the binary `date` encoding.
+const POSTGRES_EPOCH: NaiveDate = match NaiveDate::from_ymd_opt(2000, 1, 1) {
+    Some(date) => date,
+    None => unreachable!(),
+};
+
+/// Encodes the pessoas in the binary `COPY` format.
+fn copy_data(pessoas: &[Pessoa]) -> Vec<u8> {
+    fn push_field(data: &mut Vec<u8>, field: Option<&[u8]>) {
+        match field {
+            Some(field) => {
+                data.extend((field.len() as i32).to_be_bytes());
+                data.extend(field);
+            }
+            None => data.extend((-1i32).to_be_bytes()),
+        }
+    }
+    /// Dimensions, null flag, element type, then the length and lower bound of the dimension.
+    fn text_array(values: &[String]) -> Vec<u8> {
+        let mut array = Vec::new();
+        array.extend((!values.is_empty() as i32).to_be_bytes());
+        array.extend(0i32.to_be_bytes());
+        array.extend(TEXT_OID.to_be_bytes());
+        if !values.is_empty() {
+            array.extend((values.len() as i32).to_be_bytes());
+            array.extend(1i32.to_be_bytes());
+        }
+        for value in values {
+            push_field(&mut array, Some(value.as_bytes()));
+        }
+        array
+    }
+    // Signature, flags and header extension length.
+    let mut data = b"PGCOPY\n\xff\r\n\0".to_vec();
+    data.extend(0i32.to_be_bytes());
+    data.extend(0i32.to_be_bytes());
+    for pessoa in pessoas {
+        data.extend(5i16.to_be_bytes());
+        push_field(&mut data, Some(pessoa.id.as_bytes()));
+        let nascimento = (pessoa.nascimento - POSTGRES_EPOCH).num_days() as i32;
+        push_field(&mut data, Some(pessoa.nome.as_bytes()));
+        push_field(&mut data, Some(pessoa.apelido.as_bytes()));
+        push_field(&mut data, Some(&nascimento.to_be_bytes()));
+        push_field(
+            &mut data,
+            pessoa.stack.as_deref().map(text_array).as_deref(),
+        );
+    }
+    data.extend((-1i16).to_be_bytes());
+    data
+}
+
+async fn copy_pessoas(
+    conn: &mut PgConnection,
+    pessoas: &[Pessoa],
+    write_events: bool,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    sqlx::query(CREATE_STAGING_TABLE)
+        .execute(&mut *conn)
+        .await?;
+    let mut copy = conn.copy_in_raw(COPY_INTO_STAGING_TABLE).await?;
+    if let Err(err) = copy.send(copy_data(pessoas)).await {
+        let _ = copy.abort(err.to_string()).await;
+        return Err(err);
+    }
+    copy.finish().await?;
+    let insert = if write_events {
+        format!("WITH inserted AS ({INSERT_FROM_STAGING_TABLE}{INSERT_CREATED_EVENTS}")
+    } else {
+        format!("{INSERT_FROM_STAGING_TABLE}{RETURNING_IDS}")
+    };
+    sqlx::query_scalar(&insert).fetch_all(&mut *conn).await
+}
+
+/// Errors that may succeed when the same statement is tried again.
+fn is_transient(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::Io(_)
+        | sqlx::Error::Protocol(_)
+        | sqlx::Error::PoolTimedOut
+        | sqlx::Error::WorkerCrashed => true,
+        // Connection exceptions, transaction rollbacks (e.g. deadlocks), insufficient resources,
+        // operator intervention and system errors.
+        sqlx::Error::Database(err) => err
+            .code()
+            .is_some_and(|code| matches!(code.get(..2), Some("08" | "40" | "53" | "57" | "58"))),
+        _ => false,
+    }
+}
+
+/// Returns the ids of the inserted pessoas, which misses the conflicting ones.
+/// With `write_events` their events are written to the outbox as well.
+async fn try_insert(
+    conn: &mut PgConnection,
+    pessoas: &[Pessoa],
+    method: BatchInsertMethod,
+    write_events: bool,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    let mut tx = conn.begin().await?;
+    let inserted = match method {
+        BatchInsertMethod::Values => {
+            insert_query(pessoas, write_events)
+                .build_query_scalar()
+                .fetch_all(&mut *tx)
+                .await
+        }
+        BatchInsertMethod::Copy => copy_pessoas(&mut tx, pessoas, write_events).await,
+    };
+    match inserted {
+        Ok(inserted) => tx.commit().await.map(|_| inserted),
+        Err(err) => {
+            let _ = tx.rollback().await;
+            Err(err)
+        }
+    }
+}
+
+async fn insert_with_retries(
+    db: &NamedPool,
+    pool_limit: &PoolLimit,
+    pessoas: &[Pessoa],
+    method: BatchInsertMethod,
+    write_events: bool,
+    retry: &BatchRetryConfig,
+    metrics: &BatchMetrics,
+) -> Result<Vec<Uuid>, sqlx::Error> {
+    let mut attempt = 0;
+    loop {
+        let inserted = async {
+            let mut conn = pool_limit.acquire(db).await?;
+            try_insert(&mut conn, pessoas, method, write_events).await
+        };
+        match inserted.await {
+            Err(err) if attempt < retry.max_retries && is_transient(&err) => {
+                let delay = retry.base_delay * 2u32.pow(attempt.min(16));
+                tracing::warn!(message = "Retrying a batch insert.", %err, attempt, ?delay);
+                metrics.retries.add(1, &[]);
+                tokio::time::sleep(delay.min(retry.max_delay)).await;
+                attempt += 1;
+            }
+            res => return res,
+        }
+    }
+}
+
+pub(crate) struct InsertOutcome {
+    /// Misses the pessoas skipped due to a conflict.
+    pub inserted: Vec<Pessoa>,
+    pub dead_lettered: Vec<Pessoa>,
+    /// Whether every pessoa was either committed or dead lettered.
+    pub settled: bool,
+}
+
+/// Inserts the pessoas, bisecting the batch on non transient errors to isolate
+/// the rows causing them. The rows that keep failing are dead lettered.
+pub(crate) async fn insert_pessoas(
+    rinha: &MyRinha,
+    pessoas: Vec<Pessoa>,
+    retry: &BatchRetryConfig,
+) -> InsertOutcome {
+    let mut chunks = vec![pessoas];
+    let mut outcome = InsertOutcome {
+        inserted: Vec::new(),
+        dead_lettered: Vec::new(),
+        settled: true,
+    };
+    while let Some(mut chunk) = chunks.pop() {
+        match insert_with_retries(
+            rinha.read_pools.primary(),
+            &rinha.pool_limit,
+            &chunk,
+            rinha.batch_insert_method,
+            rinha.outbox,
+            retry,
+            &rinha.batch_metrics,
+        )
+        .await
+        {
+            Ok(inserted) => {
+                let inserted: HashSet<Uuid> = inserted.into_iter().collect();
+                outcome.inserted.extend(
+                    chunk
+                        .into_iter()
+                        .filter(|pessoa| inserted.contains(&pessoa.id)),
+                );
+            }
+            Err(err) if chunk.len() > 1 && !is_transient(&err) => {
+                let half = chunk.split_off(chunk.len() / 2);
+                chunks.push(half);
+                chunks.push(chunk);
+            }
+            Err(err) => {
+                tracing::error!(message = "Dead lettering pessoas.", amount = chunk.len(), %err);
+                rinha
+                    .batch_metrics
+                    .dead_letters
+                    .add(chunk.len() as u64, &[]);
+                if let Err(err) = rinha.dead_letters.push(&chunk, &err.to_string()).await {
+                    tracing::error!(message = "Failed to store dead lettered pessoas.", %err);
+                    outcome.settled = false;
+                }
+                outcome.dead_lettered.extend(chunk);
+            }
+        }
+    }
+    outcome
+}
+
+/// Spawns the insertion of the pessoas once there is a free insert permit,
+/// meanwhile the queue is not drained, which makes the new pessoas to be shed
+/// when the database is not keeping up.
+async fn batch_insert(
+    pessoas_to_insert: &mut Vec<QueuedPessoa>,
+    rinha: &Arc<MyRinha>,
+    env_values: &Arc<EnvironmentValues>,
+) {
+    if !pessoas_to_insert.is_empty() {
+        let Ok(permit) = rinha.batch_insert_permits.clone().acquire_owned().await else {
+            return;
+        };
+        let mut wal_segments = BTreeMap::<SegmentId, usize>::new();
+        for segment in pessoas_to_insert
+            .iter()
+            .filter_map(|queued| queued.wal_segment)
+        {
+            *wal_segments.entry(segment).or_default() += 1;
+        }
+        let seqs: Vec<u64> = pessoas_to_insert.iter().map(|queued| queued.seq).collect();
+        let pessoas: Vec<Pessoa> = pessoas_to_insert
+            .drain(..)
+            .map(|queued| queued.pessoa)
+            .collect();
+        let rinha = rinha.clone();
+        let env_values = env_values.clone();
+        let controller = &rinha.batch_controller;
+        let span = tracing::info_span!(
+            "batch_insert",
+            batch.size = pessoas.len(),
+            batch.target_size = controller.size(),
+            batch.wait_ms = controller.wait().as_millis() as u64,
+            batch.commit_latency_ms = controller.commit_latency().as_millis() as u64,
+        );
+        tokio::spawn(
+            async move {
+                let _permit = permit;
+                let ids: Vec<Uuid> = pessoas.iter().map(|pessoa| pessoa.id).collect();
+                let pending = pessoas.len() as u64;
+                let started = Instant::now();
+                let outcome = insert_pessoas(&rinha, pessoas, &env_values.batch_retry).await;
+                let sender = &rinha.pessoa_sender;
+                let queue_depth = sender.max_capacity() - sender.capacity();
+                rinha
+                    .batch_controller
+                    .committed(started.elapsed(), queue_depth);
+                rinha
+                    .pessoa_count
+                    .settled(pending, outcome.inserted.len() as u64);
+                let buscas: Vec<String> = outcome.inserted.iter().map(Pessoa::busca).collect();
+                rinha
+                    .pessoa_search_map
+                    .invalidate_committed(buscas.iter().map(String::as_str));
+                rinha.settle_pending(&ids, &outcome);
+                // Dead lettered pessoas are settled too, waiting for them would be in vain.
+                rinha.consistency_tokens.settled(seqs);
+                // Pessoas that could not be stored anywhere are kept at the log to be replayed.
+                if let (true, Some(wal)) = (outcome.settled, rinha.wal.as_ref()) {
+                    for (segment, amount) in wal_segments {
+                        wal.release(segment, amount);
+                    }
+                }
+            }
+            .instrument(span),
+        );
+    }
+}
+
+/// Answers `flush` once every insert spawned so far has finished.
+fn reply_flush(rinha: &MyRinha, env_values: &EnvironmentValues, flush: oneshot::Sender<()>) {
+    let permits = rinha.batch_insert_permits.clone();
+    let amount = env_values.batch_max_concurrent_inserts as u32;
+    tokio::spawn(async move {
+        // The permits are granted in order, so only after the inserts holding them finish.
+        let _ = permits.acquire_many_owned(amount).await;
+        let _ = flush.send(());
+    });
+}
+
+enum PessoaOrTimeout {
+    ReceiverClosed,
+    Flush(oneshot::Sender<()>),
+    Shutdown,
+    Timeout,
+    Pessoa(QueuedPessoa),
+}
+
+/// Batches the queued pessoas into inserts until `shutdown` fires, then the
+/// queue is closed, its remaining pessoas are inserted and the task returns
+/// once every spawned insert has finished.
+pub async fn batch_insert_task(
+    BatchQueue {
+        pessoas: mut pessoa_receiver,
+        mut flushes,
+    }: BatchQueue,
+    rinha: Arc<MyRinha>,
+    env_values: Arc<EnvironmentValues>,
+    mut shutdown: oneshot::Receiver<()>,
+) {
+    let mut pessoas_to_insert = Vec::with_capacity(env_values.batch_max_insert_size);
+    let mut closed = false;
+    // When the batch being filled up is due, counted from its first pessoa.
+    let mut flush_at = Instant::now();
+    loop {
+        let pessoa_fut = pessoa_receiver.recv();
+        let sleep_fut = tokio::time::sleep_until(flush_at);
+        match select! {
+            pessoa = pessoa_fut => pessoa.map(PessoaOrTimeout::Pessoa).unwrap_or(PessoaOrTimeout::ReceiverClosed),
+            _ = sleep_fut, if !pessoas_to_insert.is_empty() => PessoaOrTimeout::Timeout,
+            _ = &mut shutdown, if !closed => PessoaOrTimeout::Shutdown,
+            Some(flush) = flushes.recv() => PessoaOrTimeout::Flush(flush),
+        } {
+            PessoaOrTimeout::Pessoa(pessoa) => {
+                if pessoas_to_insert.is_empty() {
+                    flush_at = Instant::now() + rinha.batch_controller.wait();
+                }
+                pessoas_to_insert.push(pessoa);
+                if pessoas_to_insert.len() >= rinha.batch_controller.size() {
+                    batch_insert(&mut pessoas_to_insert, &rinha, &env_values).await
+                }
+            }
+            PessoaOrTimeout::Timeout => {
+                batch_insert(&mut pessoas_to_insert, &rinha, &env_values).await
+            }
+            // Every pessoa queued before the flush was requested is already at the channel.
+            PessoaOrTimeout::Flush(flush) => {
+                while let Ok(pessoa) = pessoa_receiver.try_recv() {
+                    pessoas_to_insert.push(pessoa);
+                    if pessoas_to_insert.len() >= rinha.batch_controller.size() {
+                        batch_insert(&mut pessoas_to_insert, &rinha, &env_values).await
+                    }
+                }
+                batch_insert(&mut pessoas_to_insert, &rinha, &env_values).await;
+                reply_flush(&rinha, &env_values, flush);
+            }
+            // The pessoas already queued are still received once it is closed.
+            PessoaOrTimeout::Shutdown => {
+                pessoa_receiver.close();
+                closed = true;
+            }
+            PessoaOrTimeout::ReceiverClosed => {
+                batch_insert(&mut pessoas_to_insert, &rinha, &env_values).await;
+                break;
+            }
+        }
+    }
+    // Every spawned insert holds a permit until it is done.
+    let _ = rinha
+        .batch_insert_permits
+        .acquire_many(env_values.batch_max_concurrent_inserts as u32)
+        .await;
+    tracing::info!("Flushed the insert queue.");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Reader<'a>(&'a [u8]);
+
+    impl Reader<'_> {
+        fn take(&mut self, len: usize) -> &[u8] {
+            let (taken, rest) = self.0.split_at(len);
+            self.0 = rest;
+            taken
+        }
+
+        fn i16(&mut self) -> i16 {
+            i16::from_be_bytes(self.take(2).try_into().unwrap())
+        }
+
+        fn i32(&mut self) -> i32 {
+            i32::from_be_bytes(self.take(4).try_into().unwrap())
+        }
+
+        fn field(&mut self) -> Option<&[u8]> {
+            match self.i32() {
+                -1 => None,
+                len => Some(self.take(len as usize)),
+            }
+        }
+    }
+
+    fn text(field: &[u8]) -> String {
+        String::from_utf8(field.to_vec()).unwrap()
+    }
+
+    fn text_array(field: &[u8]) -> Vec<String> {
+        let mut array = Reader(field);
+        let dimensions = array.i32();
+        assert_eq!(array.i32(), 0);
+        assert_eq!(array.i32(), TEXT_OID);
+        if dimensions == 0 {
+            return Vec::new();
+        }
+        let len = array.i32();
+        assert_eq!(array.i32(), 1);
+        let values = (0..len).map(|_| text(array.field().unwrap())).collect();
+        assert!(array.0.is_empty());
+        values
+    }
+
+    /// Reads the rows back the way the `COPY` into the staging table does.
+    fn read_copy_data(data: &[u8]) -> Vec<Pessoa> {
+        let mut data = Reader(data);
+        assert_eq!(data.take(11), b"PGCOPY\n\xff\r\n\0");
+        assert_eq!((data.i32(), data.i32()), (0, 0));
+        let mut pessoas = Vec::new();
+        loop {
+            match data.i16() {
+                -1 => break,
+                fields => assert_eq!(fields, 5),
+            }
+            let id = Uuid::from_slice(data.field().unwrap()).unwrap();
+            let nome = text(data.field().unwrap());
+            let apelido = text(data.field().unwrap());
+            let days = i32::from_be_bytes(data.field().unwrap().try_into().unwrap());
+            let stack = data.field().map(text_array);
+            pessoas.push(Pessoa {
+                id,
+                apelido,
+                nome,
+                nascimento: POSTGRES_EPOCH + chrono::Duration::days(days as i64),
+                stack,
+            });
+        }
+        assert!(data.0.is_empty());
+        pessoas
+    }
+
+    #[test]
+    fn copy_data_round_trips() {
+        let pessoa = |apelido: &str, nascimento, stack: Option<&[&str]>| Pessoa {
+            id: Uuid::now_v7(),
+            apelido: apelido.to_owned(),
+            nome: format!("{apelido} da Silva"),
+            nascimento,
+            stack: stack.map(|stack| stack.iter().map(|value| value.to_string()).collect()),
+        };
+        let date = |year, month, day| NaiveDate::from_ymd_opt(year, month, day).unwrap();
+        let pessoas = [
+            pessoa("zé", date(1985, 9, 23), Some(&["C#", "Node", "Oracle"])),
+            pessoa("ana", date(2000, 1, 1), None),
+            pessoa("joão", date(1999, 12, 31), Some(&[])),
+            pessoa("bia", date(2023, 10, 20), Some(&["ação"])),
+        ];
+        let read = read_copy_data(&copy_data(&pessoas));
+        assert_eq!(read.len(), pessoas.len());
+        for (read, pessoa) in read.iter().zip(pessoas.iter()) {
+            assert_eq!(read.id, pessoa.id);
+            assert_eq!(read.apelido, pessoa.apelido);
+            assert_eq!(read.nome, pessoa.nome);
+            assert_eq!(read.nascimento, pessoa.nascimento);
+            assert_eq!(read.stack, pessoa.stack);
+        }
+        assert!(read_copy_data(&copy_data(&[])).is_empty());
+    }
+}
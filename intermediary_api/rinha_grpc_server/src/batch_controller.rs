@@ -0,0 +1,207 @@
+use std::{
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use opentelemetry::metrics::{ObservableGauge, Unit};
+
+use crate::utils::env::{AdaptiveBatchConfig, EnvironmentValues};
+
+/// Weight of the latest commit at the average commit latency.
+const COMMIT_LATENCY_SMOOTHING: u64 = 5;
+
+#[derive(Default)]
+struct Decisions {
+    size: AtomicUsize,
+    wait_micros: AtomicU64,
+    /// Exponentially weighted moving average.
+    commit_latency_micros: AtomicU64,
+}
+
+/// Batch size and wait of the [`batch_insert_task`](crate::batch_insert_task).
+///
+/// They are the configured maximums unless adaptive batching is enabled, then
+/// each commit adjusts them: the batches shrink while the average commit
+/// latency is above the target and grow while the queue holds more than a
+/// batch, which is then inserted without waiting. Otherwise a batch waits about
+/// as long as a commit takes, flushing more often would only pile up inserts.
+pub struct BatchController {
+    adaptive: Option<AdaptiveBatchConfig>,
+    max_size: usize,
+    max_wait: Duration,
+    decisions: Arc<Decisions>,
+    _size: ObservableGauge<u64>,
+    _wait: ObservableGauge<u64>,
+    _commit_latency: ObservableGauge<u64>,
+}
+
+impl BatchController {
+    pub fn new(env_values: &EnvironmentValues) -> Self {
+        Self::with_limits(
+            env_values.batch_adaptive.clone(),
+            env_values.batch_max_insert_size,
+            env_values.batch_max_wait_on_insert_channel,
+        )
+    }
+
+    fn with_limits(
+        adaptive: Option<AdaptiveBatchConfig>,
+        max_size: usize,
+        max_wait: Duration,
+    ) -> Self {
+        let meter = opentelemetry::global::meter("rinha_grpc_server");
+        let decisions = Arc::new(Decisions::default());
+        decisions.size.store(max_size, Ordering::Release);
+        decisions
+            .wait_micros
+            .store(max_wait.as_micros() as u64, Ordering::Release);
+        let gauge = |name, description, unit, value: fn(&Decisions) -> u64| {
+            let decisions = decisions.clone();
+            meter
+                .u64_observable_gauge(name)
+                .with_description(description)
+                .with_unit(Unit::new(unit))
+                .with_callback(move |gauge| gauge.observe(value(&decisions), &[]))
+                .init()
+        };
+        Self {
+            _size: gauge(
+                "batch_insert.target_size",
+                "Amount of pessoas a batch is inserted at",
+                "{pessoa}",
+                |decisions| decisions.size.load(Ordering::Acquire) as u64,
+            ),
+            _wait: gauge(
+                "batch_insert.wait",
+                "Time a batch is given to fill up",
+                "ms",
+                |decisions| decisions.wait_micros.load(Ordering::Acquire) / 1000,
+            ),
+            _commit_latency: gauge(
+                "batch_insert.commit_latency",
+                "Average time taken to commit a batch",
+                "ms",
+                |decisions| decisions.commit_latency_micros.load(Ordering::Acquire) / 1000,
+            ),
+            adaptive,
+            max_size,
+            max_wait,
+            decisions,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.decisions.size.load(Ordering::Acquire)
+    }
+
+    pub fn wait(&self) -> Duration {
+        Duration::from_micros(self.decisions.wait_micros.load(Ordering::Acquire))
+    }
+
+    pub fn commit_latency(&self) -> Duration {
+        Duration::from_micros(self.decisions.commit_latency_micros.load(Ordering::Acquire))
+    }
+
+    /// Takes in the latency of a commit and the amount of pessoas queued after it.
+    pub fn committed(&self, latency: Duration, queue_depth: usize) {
+        let latency = latency.as_micros() as u64;
+        // Concurrent commits may overwrite each other, which only skips a sample.
+        let average = match self.decisions.commit_latency_micros.load(Ordering::Acquire) {
+            0 => latency,
+            average => {
+                (average * (COMMIT_LATENCY_SMOOTHING - 1) + latency) / COMMIT_LATENCY_SMOOTHING
+            }
+        };
+        self.decisions
+            .commit_latency_micros
+            .store(average, Ordering::Release);
+        let Some(adaptive) = self.adaptive.as_ref() else {
+            return;
+        };
+        let size = self.size();
+        let backlog = queue_depth >= size;
+        let size = if average > adaptive.target_commit_latency.as_micros() as u64 {
+            size - size / 4
+        } else if backlog {
+            size + size / 4 + 1
+        } else {
+            size
+        };
+        let wait = match backlog {
+            true => adaptive.min_wait,
+            false => Duration::from_micros(average).clamp(adaptive.min_wait, self.max_wait),
+        };
+        self.decisions.size.store(
+            size.clamp(adaptive.min_size, self.max_size),
+            Ordering::Release,
+        );
+        self.decisions
+            .wait_micros
+            .store(wait.as_micros() as u64, Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn adaptive_controller() -> BatchController {
+        BatchController::with_limits(
+            Some(AdaptiveBatchConfig {
+                min_size: 16,
+                min_wait: Duration::from_millis(1),
+                target_commit_latency: Duration::from_millis(50),
+            }),
+            1000,
+            Duration::from_millis(100),
+        )
+    }
+
+    #[test]
+    fn shrinks_the_batches_while_the_commits_are_slow() {
+        let controller = adaptive_controller();
+        controller.committed(Duration::from_millis(200), 2000);
+        assert_eq!(controller.size(), 750);
+        assert_eq!(controller.wait(), Duration::from_millis(1));
+        for _ in 0..32 {
+            controller.committed(Duration::from_millis(200), 0);
+        }
+        assert_eq!(controller.size(), 16);
+        assert_eq!(controller.wait(), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn grows_the_batches_while_the_queue_holds_more_than_one() {
+        let controller = adaptive_controller();
+        for _ in 0..32 {
+            controller.committed(Duration::from_millis(200), 0);
+        }
+        assert_eq!(controller.size(), 16);
+        // The average latency takes a few commits to fall below the target.
+        let mut sizes = Vec::new();
+        for _ in 0..16 {
+            controller.committed(Duration::from_millis(10), controller.size());
+            sizes.push(controller.size());
+        }
+        assert!(sizes.windows(2).all(|sizes| sizes[0] <= sizes[1]));
+        assert!(controller.size() > 16);
+        assert_eq!(controller.wait(), Duration::from_millis(1));
+
+        let size = controller.size();
+        controller.committed(Duration::from_millis(10), size - 1);
+        assert_eq!(controller.size(), size);
+        assert_eq!(controller.wait(), controller.commit_latency());
+    }
+
+    #[test]
+    fn keeps_the_maximums_unless_adaptive() {
+        let controller = BatchController::with_limits(None, 1000, Duration::from_millis(100));
+        controller.committed(Duration::from_millis(200), 2000);
+        assert_eq!(controller.size(), 1000);
+        assert_eq!(controller.wait(), Duration::from_millis(100));
+        assert_eq!(controller.commit_latency(), Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,288 @@
+use moka::sync::Cache;
+use opentelemetry::{
+    metrics::{Counter, ObservableGauge, Unit},
+    KeyValue,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{models::pessoa::PESSOA_SEARCH_LIMIT, utils::env::CacheConfig};
+
+/// A size bounded cache of JSON payloads.
+///
+/// Eviction is driven by a TinyLFU admission policy in front of a LRU,
+/// so one-off keys (e.g. search terms that are never repeated) are not
+/// able to flush the frequently accessed ones out of the cache.
+pub struct BoundedCache {
+    inner: Cache<String, String>,
+    hits: Counter<u64>,
+    misses: Counter<u64>,
+    attributes: Arc<[KeyValue]>,
+    _size: ObservableGauge<u64>,
+    _entries: ObservableGauge<u64>,
+}
+
+impl BoundedCache {
+    pub fn new(name: &'static str, config: &CacheConfig) -> Self {
+        let meter = opentelemetry::global::meter("rinha_grpc_server");
+        let attributes: Arc<[KeyValue]> = Arc::new([KeyValue::new("cache", name)]);
+        let evictions = meter
+            .u64_counter("cache.evictions")
+            .with_description("Entries removed due to the size bound or to the TTL")
+            .init();
+        let mut builder = Cache::builder();
+        builder =
+            match config.max_entries {
+                Some(max_entries) => builder.max_capacity(max_entries),
+                None => builder.max_capacity(config.max_bytes).weigher(
+                    |key: &String, value: &String| {
+                        (key.len() + value.len()).try_into().unwrap_or(u32::MAX)
+                    },
+                ),
+            };
+        if let Some(ttl) = config.ttl {
+            builder = builder.time_to_live(ttl);
+        }
+        let eviction_attributes = attributes.clone();
+        let inner = builder
+            .eviction_listener(move |_, _, cause| {
+                if cause.was_evicted() {
+                    evictions.add(1, &eviction_attributes);
+                }
+            })
+            .build();
+        let size = {
+            let (inner, attributes) = (inner.clone(), attributes.clone());
+            meter
+                .u64_observable_gauge("cache.size")
+                .with_description("Weighted size of the cache")
+                .with_unit(Unit::new(if config.max_entries.is_some() {
+                    "{entry}"
+                } else {
+                    "By"
+                }))
+                .with_callback(move |gauge| gauge.observe(inner.weighted_size(), &attributes))
+                .init()
+        };
+        let entries = {
+            let (inner, attributes) = (inner.clone(), attributes.clone());
+            meter
+                .u64_observable_gauge("cache.entries")
+                .with_description("Amount of entries in the cache")
+                .with_callback(move |gauge| gauge.observe(inner.entry_count(), &attributes))
+                .init()
+        };
+        Self {
+            inner,
+            hits: meter.u64_counter("cache.hits").init(),
+            misses: meter.u64_counter("cache.misses").init(),
+            attributes,
+            _size: size,
+            _entries: entries,
+        }
+    }
+
+    pub fn get(&self, key: &str) -> Option<String> {
+        let value = self.inner.get(key);
+        if value.is_some() {
+            self.hits.add(1, &self.attributes);
+        } else {
+            self.misses.add(1, &self.attributes);
+        }
+        value
+    }
+
+    pub fn insert(&self, key: String, value: String) {
+        self.inner.insert(key, value);
+    }
+
+    pub fn remove(&self, key: &str) {
+        self.inner.invalidate(key);
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.inner.contains_key(key)
+    }
+
+    pub fn entry_count(&self) -> u64 {
+        self.inner.entry_count()
+    }
+
+    pub fn entries(&self) -> impl Iterator<Item = (Arc<String>, String)> + '_ {
+        self.inner.iter()
+    }
+}
+
+/// Search results cache that stays consistent with the inserted pessoas.
+///
+/// A result with [`PESSOA_SEARCH_LIMIT`] pessoas is still a valid answer after
+/// any insert, so only the terms with fewer results are tracked and dropped
+/// when a pessoa whose `busca` contains them is created or committed. Results
+/// fetched while a batch was being committed are never cached, which is
+/// enforced by comparing the `epoch` before and after the query.
+pub struct SearchCache {
+    results: BoundedCache,
+    unsaturated_terms: Mutex<UnsaturatedTerms>,
+    epoch: AtomicU64,
+}
+
+/// The tracked terms keyed by their first three characters, or by the whole
+/// term when shorter, so a `busca` only visits the terms that may start at
+/// each of its characters rather than every tracked one.
+#[derive(Default)]
+struct UnsaturatedTerms {
+    by_prefix: HashMap<String, HashSet<String>>,
+    /// `%`, `_` and `\` have a special meaning to `LIKE`, so those terms, and
+    /// the empty one, are dropped by every invalidation.
+    patterns: HashSet<String>,
+    len: usize,
+}
+
+impl UnsaturatedTerms {
+    fn insert(&mut self, term: String) {
+        let inserted = if term.is_empty() || term.contains(['%', '_', '\\']) {
+            self.patterns.insert(term)
+        } else {
+            self.by_prefix
+                .entry(prefix(&term).to_owned())
+                .or_default()
+                .insert(term)
+        };
+        self.len += inserted as usize;
+    }
+
+    /// Removes and returns the terms contained in `busca`, along with the patterns.
+    fn take_matching(&mut self, busca: &str) -> Vec<String> {
+        let mut matching: Vec<String> = self.patterns.drain().collect();
+        for (start, _) in busca.char_indices() {
+            let rest = &busca[start..];
+            // The ends of its first one, two and three characters.
+            let ends = rest.char_indices().map(|(end, _)| end).skip(1);
+            for end in ends.chain([rest.len()]).take(3) {
+                let Some(terms) = self.by_prefix.get_mut(&rest[..end]) else {
+                    continue;
+                };
+                terms.retain(|term| {
+                    let matches = rest.starts_with(term.as_str());
+                    if matches {
+                        matching.push(term.clone());
+                    }
+                    !matches
+                });
+                if terms.is_empty() {
+                    self.by_prefix.remove(&rest[..end]);
+                }
+            }
+        }
+        self.len -= matching.len();
+        matching
+    }
+
+    /// Drops the terms whose results were evicted.
+    fn retain(&mut self, mut cached: impl FnMut(&str) -> bool) {
+        self.patterns.retain(|term| cached(term));
+        self.by_prefix.retain(|_, terms| {
+            terms.retain(|term| cached(term));
+            !terms.is_empty()
+        });
+        self.len = self.patterns.len() + self.by_prefix.values().map(HashSet::len).sum::<usize>();
+    }
+}
+
+fn prefix(term: &str) -> &str {
+    term.char_indices()
+        .nth(3)
+        .map_or(term, |(end, _)| &term[..end])
+}
+
+impl SearchCache {
+    pub fn new(config: &CacheConfig) -> Self {
+        Self {
+            results: BoundedCache::new("pessoa_search", config),
+            unsaturated_terms: Default::default(),
+            epoch: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, term: &str) -> Option<String> {
+        self.results.get(term)
+    }
+
+    /// Must be read before querying the results that are given to [`Self::insert`].
+    pub fn epoch(&self) -> u64 {
+        self.epoch.load(Ordering::Acquire)
+    }
+
+    pub fn insert(&self, term: String, json: String, len: usize, epoch: u64) {
+        if len < PESSOA_SEARCH_LIMIT {
+            let mut terms = self.unsaturated_terms.lock().unwrap();
+            terms.insert(term.clone());
+            // The evicted results leave their terms behind, which are swept once they pile up.
+            if terms.len > 2 * (self.results.entry_count() as usize).max(1024) {
+                terms.retain(|term| self.results.contains_key(term));
+            }
+        }
+        if self.epoch() == epoch {
+            self.results.insert(term.clone(), json);
+            // A commit that happened in between could have missed this entry.
+            if self.epoch() != epoch {
+                self.results.remove(&term);
+            }
+        }
+    }
+
+    /// Drops the cached results that could be missing a pessoa with one of the given `buscas`.
+    pub fn invalidate<'a>(&self, buscas: impl IntoIterator<Item = &'a str>) {
+        let mut terms = self.unsaturated_terms.lock().unwrap();
+        for busca in buscas {
+            for term in terms.take_matching(busca) {
+                self.results.remove(&term);
+            }
+        }
+    }
+
+    /// Invalidates the results of a committed batch and every query that raced with it.
+    pub fn invalidate_committed<'a>(&self, buscas: impl IntoIterator<Item = &'a str>) {
+        self.epoch.fetch_add(1, Ordering::AcqRel);
+        self.invalidate(buscas);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tracked(terms: &[&str]) -> UnsaturatedTerms {
+        let mut tracked = UnsaturatedTerms::default();
+        for term in terms {
+            tracked.insert(term.to_string());
+        }
+        tracked
+    }
+
+    #[test]
+    fn takes_the_terms_contained_in_the_busca() {
+        let mut terms = tracked(&["jo", "joão", "ãos", "rust", "sql", "a_b", "x"]);
+        let mut taken = terms.take_matching("joãosilvarust");
+        taken.sort();
+        assert_eq!(taken, ["a_b", "jo", "joão", "rust", "ãos"]);
+        assert_eq!(terms.len, 2);
+        assert!(terms.take_matching("joãosilvarust").is_empty());
+        assert_eq!(terms.take_matching("postgresql"), ["sql"]);
+    }
+
+    #[test]
+    fn drops_the_terms_no_longer_cached() {
+        let mut terms = tracked(&["ana", "anabela", "%", "bo"]);
+        terms.retain(|term| term.starts_with('a'));
+        assert_eq!(terms.len, 2);
+        let mut taken = terms.take_matching("anabelabo");
+        taken.sort();
+        assert_eq!(taken, ["ana", "anabela"]);
+    }
+}
@@ -0,0 +1,131 @@
+use std::{collections::BTreeSet, sync::Mutex, time::Duration};
+
+use tokio::sync::watch;
+
+/// Hands out a token for every queued pessoa and lets the reads given one wait
+/// for the pessoa to be committed.
+///
+/// The tokens are `{instance}.{seq}`, `seq` increasing with every queued
+/// pessoa. As the batches commit out of order, the pessoas still queued are
+/// tracked and the reads wait for every `seq` up to theirs to be settled. The
+/// instance is random, so the tokens of other instances, or of this one before
+/// a restart, are ignored. The latter ones were committed at shutdown anyway.
+pub struct ConsistencyTokens {
+    instance: String,
+    state: Mutex<Pending>,
+    /// Every `seq` up to this one is settled.
+    settled: watch::Sender<u64>,
+    timeout: Duration,
+}
+
+#[derive(Default)]
+struct Pending {
+    last_seq: u64,
+    seqs: BTreeSet<u64>,
+}
+
+impl ConsistencyTokens {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            instance: format!("{:016x}", rand::random::<u64>()),
+            state: Default::default(),
+            settled: watch::channel(0).0,
+            timeout,
+        }
+    }
+
+    /// Returns the `seq` of a pessoa about to be queued.
+    pub fn queued(&self) -> u64 {
+        let mut state = self.state.lock().unwrap();
+        state.last_seq += 1;
+        let seq = state.last_seq;
+        state.seqs.insert(seq);
+        seq
+    }
+
+    pub fn token(&self, seq: u64) -> String {
+        format!("{}.{seq}", self.instance)
+    }
+
+    /// Records the pessoas either committed or dead lettered, the reads waiting on them can go on.
+    pub fn settled(&self, seqs: impl IntoIterator<Item = u64>) {
+        let mut state = self.state.lock().unwrap();
+        for seq in seqs {
+            state.seqs.remove(&seq);
+        }
+        let settled = match state.seqs.first() {
+            Some(first) => first - 1,
+            None => state.last_seq,
+        };
+        self.settled.send_if_modified(|current| {
+            let modified = *current != settled;
+            *current = settled;
+            modified
+        });
+    }
+
+    /// Waits for the pessoas queued up to the token to be settled, returning
+    /// `false` when that takes too long.
+    pub async fn wait(&self, token: &str) -> bool {
+        let seq = match token.split_once('.') {
+            Some((instance, seq)) if instance == self.instance => seq.parse().unwrap_or(0),
+            _ => return true,
+        };
+        let mut settled = self.settled.subscribe();
+        let waited =
+            tokio::time::timeout(self.timeout, settled.wait_for(|settled| *settled >= seq)).await;
+        waited.is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hands_out_tokens_of_the_instance() {
+        let tokens = ConsistencyTokens::new(Duration::from_secs(1));
+        let (first, second) = (tokens.queued(), tokens.queued());
+        assert_eq!((first, second), (1, 2));
+        let token = tokens.token(second);
+        assert_eq!(token.split_once('.'), Some((tokens.instance.as_str(), "2")));
+        assert_ne!(
+            ConsistencyTokens::new(Duration::from_secs(1)).instance,
+            tokens.instance
+        );
+    }
+
+    #[tokio::test]
+    async fn ignores_the_tokens_of_other_instances() {
+        let tokens = ConsistencyTokens::new(Duration::from_secs(60));
+        let seq = tokens.queued();
+        let other = ConsistencyTokens::new(Duration::from_secs(60));
+        assert!(tokens.wait(&other.token(seq)).await);
+        assert!(tokens.wait("not a token").await);
+        assert!(tokens.wait(&format!("{}.x", tokens.instance)).await);
+    }
+
+    #[tokio::test]
+    async fn waits_for_every_pessoa_queued_up_to_the_token() {
+        let tokens = ConsistencyTokens::new(Duration::from_secs(60));
+        let (first, second) = (tokens.queued(), tokens.queued());
+        let token = tokens.token(second);
+        let waited = futures::join!(tokens.wait(&token), async {
+            // Settled out of order, the first one is still pending.
+            tokens.settled([second]);
+            tokio::task::yield_now().await;
+            assert_eq!(*tokens.settled.borrow(), 0);
+            tokens.settled([first]);
+        });
+        assert!(waited.0);
+        assert_eq!(*tokens.settled.borrow(), 2);
+        assert!(tokens.wait(&token).await);
+    }
+
+    #[tokio::test]
+    async fn gives_up_once_the_timeout_elapses() {
+        let tokens = ConsistencyTokens::new(Duration::from_millis(10));
+        let seq = tokens.queued();
+        assert!(!tokens.wait(&tokens.token(seq)).await);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::{
+    models::pessoa::COUNT_PESSOAS,
+    pool::NamedPool,
+    query_monitor::{QueryKind, QueryMonitor},
+};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Mutex,
+};
+
+/// Amount of pessoas, both committed and waiting to be inserted, answered without a `COUNT`.
+///
+/// The committed amount is seeded from the database and periodically
+/// [reconciled](Self::reconcile) with it, as other instances insert pessoas
+/// too. The pessoas inserted here while a reconcile counts are added to its
+/// count, so the ones committed right before the `COUNT` took its snapshot
+/// are counted twice until the next reconcile.
+#[derive(Default)]
+pub struct PessoaCounter {
+    committed: AtomicU64,
+    pending: AtomicU64,
+    /// Pessoas inserted here so far, held while the committed amount is updated.
+    inserted: Mutex<u64>,
+}
+
+impl PessoaCounter {
+    pub fn get(&self) -> u64 {
+        self.committed.load(Ordering::Acquire) + self.pending.load(Ordering::Acquire)
+    }
+
+    pub fn enqueued(&self) {
+        self.pending.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Records `pending` queued pessoas leaving the queue, of which `inserted` were committed.
+    pub fn settled(&self, pending: u64, inserted: u64) {
+        let mut total = self.inserted.lock().unwrap();
+        *total += inserted;
+        self.committed.fetch_add(inserted, Ordering::AcqRel);
+        self.pending.fetch_sub(pending, Ordering::AcqRel);
+    }
+
+    /// Replaces the committed amount with the one at the database plus the
+    /// pessoas inserted meanwhile, which is returned.
+    pub async fn reconcile(
+        &self,
+        db: &NamedPool,
+        queries: &QueryMonitor,
+    ) -> Result<u64, sqlx::Error> {
+        let counted = async {
+            let mut conn = queries.acquire(db).await?;
+            let before = *self.inserted.lock().unwrap();
+            let (count,) = sqlx::query_as::<_, (i64,)>(COUNT_PESSOAS)
+                .fetch_one(&mut *conn)
+                .await?;
+            Ok((before, count as u64))
+        };
+        let (before, count) = queries
+            .run(QueryKind::CountPessoa, COUNT_PESSOAS, None, counted)
+            .await?;
+        Ok(self.reconciled(before, count))
+    }
+
+    /// Takes in a `count` taken once `before` pessoas were inserted here.
+    fn reconciled(&self, before: u64, count: u64) -> u64 {
+        let inserted = self.inserted.lock().unwrap();
+        let committed = count + (*inserted - before);
+        self.committed.store(committed, Ordering::Release);
+        committed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn settles_the_committed_pessoas() {
+        let counter = PessoaCounter::default();
+        counter.enqueued();
+        counter.enqueued();
+        counter.settled(2, 1);
+        assert_eq!(counter.get(), 1);
+    }
+
+    #[test]
+    fn adds_the_pessoas_inserted_while_counting() {
+        let counter = PessoaCounter::default();
+        counter.enqueued();
+        counter.enqueued();
+        counter.enqueued();
+        counter.settled(1, 1);
+        let before = *counter.inserted.lock().unwrap();
+        // Inserted after the `COUNT` took its snapshot, by this and other instances.
+        counter.settled(1, 1);
+        assert_eq!(counter.reconciled(before, 10), 11);
+        assert_eq!(counter.get(), 12);
+        counter.settled(1, 1);
+        assert_eq!(counter.get(), 12);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{io, path::PathBuf};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{self, OpenOptions},
+    io::AsyncWriteExt,
+    sync::Mutex,
+};
+
+use crate::models::pessoa::Pessoa;
+
+#[derive(Serialize, Deserialize)]
+struct DeadLetter {
+    pessoa: Pessoa,
+    error: String,
+    failed_at: chrono::DateTime<chrono::Utc>,
+}
+
+/// NDJSON file of the pessoas that could not be inserted.
+///
+/// A file is used instead of a table since the database being unavailable is
+/// one of the reasons for a pessoa to be dead lettered.
+pub struct DeadLetters {
+    path: PathBuf,
+    lock: Mutex<()>,
+    replay_lock: Mutex<()>,
+}
+
+impl DeadLetters {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            lock: Mutex::new(()),
+            replay_lock: Mutex::new(()),
+        }
+    }
+
+    pub async fn push(&self, pessoas: &[Pessoa], error: &str) -> io::Result<()> {
+        let failed_at = chrono::Utc::now();
+        let mut lines = Vec::new();
+        for pessoa in pessoas {
+            serde_json::to_writer(
+                &mut lines,
+                &DeadLetter {
+                    pessoa: pessoa.clone(),
+                    error: error.into(),
+                    failed_at,
+                },
+            )?;
+            lines.push(b'\n');
+        }
+        self.append(&lines).await
+    }
+
+    async fn append(&self, lines: &[u8]) -> io::Result<()> {
+        let _guard = self.lock.lock().await;
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(lines).await?;
+        file.sync_data().await
+    }
+
+    /// Takes every dead lettered pessoa out of the file and gives them to `replay`,
+    /// those failing again are expected to be pushed back by it. `replay`
+    /// returns whether it settled every pessoa, either storing or pushing it
+    /// back. Returns the amount of pessoas replayed along with the output of `replay`.
+    ///
+    /// The pessoas are kept at a `.replaying` file until `replay` settles them,
+    /// so they are picked up by the next replay if it doesn't or if the process
+    /// stops in between. The lines that can't be read are given back to the file.
+    pub async fn replay<F, Fut, T>(&self, replay: F) -> io::Result<(usize, T)>
+    where
+        F: FnOnce(Vec<Pessoa>) -> Fut,
+        Fut: std::future::Future<Output = (T, bool)>,
+    {
+        let _replay_guard = self.replay_lock.lock().await;
+        let replaying = self.path.with_extension("replaying");
+        let mut content = match fs::read(&replaying).await {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        {
+            let _guard = self.lock.lock().await;
+            match fs::read(&self.path).await {
+                Ok(pushed) => {
+                    content.extend(pushed);
+                    fs::write(&replaying, &content).await?;
+                    fs::remove_file(&self.path).await?;
+                }
+                Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err),
+            }
+        }
+        let (mut pessoas, mut invalid) = (Vec::new(), Vec::new());
+        for line in content
+            .split(|byte| *byte == b'\n')
+            .filter(|line| !line.is_empty())
+        {
+            match serde_json::from_slice::<DeadLetter>(line) {
+                Ok(dead_letter) => pessoas.push(dead_letter.pessoa),
+                Err(err) => {
+                    tracing::error!(message = "Keeping an invalid dead letter.", %err);
+                    invalid.extend(line);
+                    invalid.push(b'\n');
+                }
+            }
+        }
+        let amount = pessoas.len();
+        let (output, settled) = replay(pessoas).await;
+        if !settled {
+            return Ok((amount, output));
+        }
+        if !invalid.is_empty() {
+            self.append(&invalid).await?;
+        }
+        match fs::remove_file(&replaying).await {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok((amount, output)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn pessoa(apelido: &str) -> Pessoa {
+        Pessoa {
+            id: Uuid::now_v7(),
+            apelido: apelido.to_owned(),
+            nome: "José".to_owned(),
+            nascimento: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            stack: None,
+        }
+    }
+
+    fn apelidos(pessoas: &[Pessoa]) -> Vec<&str> {
+        pessoas
+            .iter()
+            .map(|pessoa| pessoa.apelido.as_str())
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn keeps_the_pessoas_of_an_unsettled_replay() {
+        let dir = std::env::temp_dir().join(format!("rinha-dead-letters-{}", Uuid::new_v4()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let dead_letters = DeadLetters::new(dir.join("dead_letters.ndjson"));
+        dead_letters
+            .push(&[pessoa("ana"), pessoa("zé")], "poisoned")
+            .await
+            .unwrap();
+        dead_letters.append(b"not json\n").await.unwrap();
+
+        let (replayed, ()) = dead_letters
+            .replay(|pessoas| async move {
+                assert_eq!(apelidos(&pessoas), ["ana", "zé"]);
+                ((), false)
+            })
+            .await
+            .unwrap();
+        assert_eq!(replayed, 2);
+        assert!(dir.join("dead_letters.replaying").exists());
+
+        // Given to the next replay along with the pessoas pushed meanwhile.
+        dead_letters
+            .push(&[pessoa("bia")], "poisoned")
+            .await
+            .unwrap();
+        dead_letters
+            .replay(|pessoas| async move {
+                assert_eq!(apelidos(&pessoas), ["ana", "zé", "bia"]);
+                ((), true)
+            })
+            .await
+            .unwrap();
+        assert!(!dir.join("dead_letters.replaying").exists());
+        let kept = std::fs::read(dir.join("dead_letters.ndjson")).unwrap();
+        assert_eq!(kept, b"not json\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,115 @@
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::{
+    fs::OpenOptions,
+    io::{self, AsyncWriteExt},
+};
+
+use crate::utils::env::EventSinkConfig;
+
+pub type SinkError = Box<dyn std::error::Error + Send + Sync>;
+
+/// An event read from the outbox.
+#[derive(Serialize)]
+pub struct Event {
+    /// Unique, though the events are delivered in the order of their transactions instead.
+    pub seq: i64,
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub created_at: DateTime<Utc>,
+    pub data: serde_json::Value,
+}
+
+/// Where the events of the outbox are delivered to.
+///
+/// A delivery either succeeds for every event or is retried as a whole, so a
+/// sink may see the same event more than once and should be idempotent by `seq`.
+#[tonic::async_trait]
+pub trait EventSink: Send + Sync {
+    /// Identifies the position of the sink at the outbox, kept across restarts.
+    fn name(&self) -> String;
+
+    async fn deliver(&self, events: &[Event]) -> Result<(), SinkError>;
+}
+
+pub fn from_config(config: &EventSinkConfig) -> Result<Box<dyn EventSink>, reqwest::Error> {
+    Ok(match config {
+        EventSinkConfig::Stdout => Box::new(StdoutSink),
+        EventSinkConfig::File(path) => Box::new(FileSink { path: path.clone() }),
+        EventSinkConfig::Webhook { url, timeout } => Box::new(WebhookSink {
+            url: url.clone(),
+            client: reqwest::Client::builder().timeout(*timeout).build()?,
+        }),
+    })
+}
+
+fn ndjson(events: &[Event]) -> Result<Vec<u8>, serde_json::Error> {
+    let mut lines = Vec::new();
+    for event in events {
+        serde_json::to_writer(&mut lines, event)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+pub struct StdoutSink;
+
+#[tonic::async_trait]
+impl EventSink for StdoutSink {
+    fn name(&self) -> String {
+        "stdout".into()
+    }
+
+    async fn deliver(&self, events: &[Event]) -> Result<(), SinkError> {
+        let mut stdout = io::stdout();
+        stdout.write_all(&ndjson(events)?).await?;
+        Ok(stdout.flush().await?)
+    }
+}
+
+pub struct FileSink {
+    path: PathBuf,
+}
+
+#[tonic::async_trait]
+impl EventSink for FileSink {
+    fn name(&self) -> String {
+        format!("file:{}", self.path.display())
+    }
+
+    async fn deliver(&self, events: &[Event]) -> Result<(), SinkError> {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .await?;
+        file.write_all(&ndjson(events)?).await?;
+        Ok(file.sync_data().await?)
+    }
+}
+
+pub struct WebhookSink {
+    url: String,
+    client: reqwest::Client,
+}
+
+#[tonic::async_trait]
+impl EventSink for WebhookSink {
+    fn name(&self) -> String {
+        format!("webhook:{}", self.url)
+    }
+
+    /// Posts the events as a JSON array, any status other than a 2xx is a failure.
+    async fn deliver(&self, events: &[Event]) -> Result<(), SinkError> {
+        self.client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(serde_json::to_vec(events)?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -1,5 +1,10 @@
+mod migrations;
 mod models;
+mod pool;
+mod query_monitor;
+mod read_pools;
 mod utils;
+pub use migrations::migrate;
 pub mod rinha {
     tonic::include_proto!("rinha");
     pub(crate) const FILE_DESCRIPTOR_SET: &[u8] =
@@ -11,7 +16,41 @@ mod without_cache;
 #[cfg(feature = "without_cache_and_batch")]
 pub use without_cache::*;
 
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod admin;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod batch;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod batch_controller;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod cache;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod consistency;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod counter;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod dead_letter;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod event_sink;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod listener;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod outbox;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod search_index;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod singleflight;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod snapshot;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod wal;
+#[cfg(not(feature = "without_cache_and_batch"))]
+mod webhooks;
 #[cfg(not(feature = "without_cache_and_batch"))]
 mod with_cache;
 #[cfg(not(feature = "without_cache_and_batch"))]
+pub use admin::MyRinhaAdmin;
+#[cfg(not(feature = "without_cache_and_batch"))]
+pub use batch::{batch_insert_task, BatchQueue, QueuedPessoa};
+#[cfg(not(feature = "without_cache_and_batch"))]
 pub use with_cache::*;
@@ -0,0 +1,245 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::TryStreamExt;
+use opentelemetry::{metrics::Counter, KeyValue};
+use serde::Deserialize;
+use sqlx::postgres::{PgConnectOptions, PgListener};
+use uuid::{Builder, Uuid};
+
+use crate::{models::pessoa::Pessoa, utils::env::CacheCoherencyConfig, with_cache::MyRinha};
+
+/// Channel notified by the `PESSOAS_CREATED` trigger of every committed pessoa.
+const PESSOAS_CREATED_CHANNEL: &str = "pessoas_created";
+
+/// The `PESSOAS_CREATED` trigger only notifies the pessoas inserted by the
+/// sessions turning this setting on, so nothing is sent when nobody listens.
+const NOTIFY_SETTING: &str = "rinha.cache_coherency";
+
+/// Options of the connections whose inserted pessoas are notified. Every
+/// instance sharing the database has to use them for the others to be coherent.
+pub fn notifying(options: PgConnectOptions) -> PgConnectOptions {
+    options.options([(NOTIFY_SETTING, "on")])
+}
+
+/// The payload of a notification, which is just the id of the pessoas not fitting one.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Created {
+    Pessoa(Pessoa),
+    Id { id: Uuid },
+}
+
+struct ListenerMetrics {
+    taken_in: Counter<u64>,
+    reconnects: Counter<u64>,
+}
+
+impl ListenerMetrics {
+    fn new() -> Self {
+        let meter = opentelemetry::global::meter("rinha_grpc_server");
+        Self {
+            taken_in: meter
+                .u64_counter("cache_coherency.taken_in")
+                .with_description("Pessoas committed by other instances added to the caches")
+                .init(),
+            reconnects: meter
+                .u64_counter("cache_coherency.reconnects")
+                .with_description("Connections of the listener lost and established again")
+                .init(),
+        }
+    }
+}
+
+/// Keeps the caches coherent with the pessoas committed by the other instances.
+///
+/// Notifications sent while the connection is down are lost, so after
+/// (re)connecting the pessoas created since shortly before the connection was
+/// lost are read from the database. As their ids are UUIDv7 that is a range of
+/// the id index. Taking in a pessoa twice is harmless, the known ones are skipped.
+pub async fn listen_task(rinha: Arc<MyRinha>, config: CacheCoherencyConfig) {
+    let metrics = ListenerMetrics::new();
+    // The pessoas committed while the server was booting are recovered as well.
+    let mut lost_at = SystemTime::now();
+    loop {
+        let mut listener = match listen(&rinha, lost_at - config.recovery_window, &metrics).await {
+            Ok(listener) => listener,
+            Err(err) => {
+                tracing::warn!(message = "Failed to listen to the created pessoas.", %err);
+                tokio::time::sleep(config.reconnect_delay).await;
+                continue;
+            }
+        };
+        loop {
+            match listener.try_recv().await {
+                Ok(Some(notification)) => {
+                    if let Err(err) = take_in(&rinha, notification.payload(), &metrics).await {
+                        tracing::warn!(message = "Failed to take in a created pessoa.", %err);
+                    }
+                }
+                Ok(None) => break,
+                Err(err) => {
+                    tracing::warn!(message = "Lost the listener connection.", %err);
+                    break;
+                }
+            }
+        }
+        lost_at = SystemTime::now();
+        metrics.reconnects.add(1, &[]);
+    }
+}
+
+/// Starts listening and then recovers the pessoas created since `since`, so none falls in between.
+async fn listen(
+    rinha: &MyRinha,
+    since: SystemTime,
+    metrics: &ListenerMetrics,
+) -> Result<PgListener, sqlx::Error> {
+    let mut listener = PgListener::connect_with(&rinha.db).await?;
+    listener.listen(PESSOAS_CREATED_CHANNEL).await?;
+    let since = since
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis();
+    let first_id = Builder::from_unix_timestamp_millis(since as u64, &[0; 10]).into_uuid();
+    let mut pessoas = sqlx::query_as::<_, Pessoa>(
+        "SELECT id, apelido, nome, nascimento, stack FROM pessoas \
+        WHERE id >= $1 AND pessoa_created_at(id) IS NOT NULL;",
+    )
+    .bind(first_id)
+    .fetch(&rinha.db);
+    let mut amount = 0;
+    while let Some(pessoa) = pessoas.try_next().await? {
+        if rinha.committed_elsewhere(&pessoa) {
+            amount += 1;
+        }
+    }
+    metrics
+        .taken_in
+        .add(amount, &[KeyValue::new("source", "recovery")]);
+    tracing::info!(
+        message = "Listening to the created pessoas.",
+        recovered = amount
+    );
+    Ok(listener)
+}
+
+async fn take_in(
+    rinha: &MyRinha,
+    payload: &str,
+    metrics: &ListenerMetrics,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let pessoa = match serde_json::from_str(payload)? {
+        Created::Pessoa(pessoa) => pessoa,
+        Created::Id { id } => {
+            sqlx::query_as::<_, Pessoa>(
+                "SELECT id, apelido, nome, nascimento, stack FROM pessoas WHERE id = $1;",
+            )
+            .bind(id)
+            .fetch_one(&rinha.db)
+            .await?
+        }
+    };
+    if rinha.committed_elsewhere(&pessoa) {
+        metrics
+            .taken_in
+            .add(1, &[KeyValue::new("source", "notification")]);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::NaiveDate;
+    use sqlx::PgPool;
+
+    use super::*;
+
+    fn pessoa(id: Uuid, apelido: &str) -> Pessoa {
+        Pessoa {
+            id,
+            apelido: apelido.to_owned(),
+            nome: "José".to_owned(),
+            nascimento: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            stack: None,
+        }
+    }
+
+    fn is_cached(rinha: &MyRinha, pessoa: &Pessoa) -> bool {
+        rinha.pessoa_by_id_map.get(&pessoa.id.to_string()).is_some()
+    }
+
+    #[tokio::test]
+    async fn takes_in_only_the_unknown_pessoas() {
+        let rinha = MyRinha::for_tests(PgPool::connect_lazy("postgres://localhost/rinha").unwrap());
+        let metrics = ListenerMetrics::new();
+        // Created by this instance.
+        rinha.pessoa_by_apelido_exists_set.insert("ana".to_owned());
+        let ana = pessoa(Uuid::now_v7(), "ana");
+        take_in(&rinha, &serde_json::to_string(&ana).unwrap(), &metrics)
+            .await
+            .unwrap();
+        assert!(!is_cached(&rinha, &ana));
+
+        let epoch = rinha.pessoa_search_map.epoch();
+        rinha
+            .pessoa_search_map
+            .insert("bi".to_owned(), "[]".to_owned(), 0, epoch);
+        let bia = pessoa(Uuid::now_v7(), "bia");
+        take_in(&rinha, &serde_json::to_string(&bia).unwrap(), &metrics)
+            .await
+            .unwrap();
+        assert!(is_cached(&rinha, &bia));
+        assert!(rinha.pessoa_by_apelido_exists_set.contains("bia"));
+        assert!(rinha.pessoa_search_map.get("bi").is_none());
+        // Taking it in again, from a notification and the recovery, is harmless.
+        assert!(!rinha.committed_elsewhere(&bia));
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    async fn recovers_the_pessoas_created_within_the_window(db: PgPool) {
+        let rinha = MyRinha::for_tests(db.clone());
+        let metrics = ListenerMetrics::new();
+        let an_hour_ago = SystemTime::now() - Duration::from_secs(3600);
+        let millis = an_hour_ago.duration_since(UNIX_EPOCH).unwrap().as_millis() as u64;
+        let old = pessoa(
+            Builder::from_unix_timestamp_millis(millis, &[7; 10]).into_uuid(),
+            "old",
+        );
+        let (recent, known) = (
+            pessoa(Uuid::now_v7(), "recent"),
+            pessoa(Uuid::now_v7(), "known"),
+        );
+        for pessoa in [&old, &recent, &known] {
+            sqlx::query(
+                "INSERT INTO pessoas (id, apelido, nome, nascimento) VALUES ($1, $2, $3, $4);",
+            )
+            .bind(pessoa.id)
+            .bind(&pessoa.apelido)
+            .bind(&pessoa.nome)
+            .bind(pessoa.nascimento)
+            .execute(&db)
+            .await
+            .unwrap();
+        }
+        rinha
+            .pessoa_by_apelido_exists_set
+            .insert("known".to_owned());
+
+        listen(
+            &rinha,
+            SystemTime::now() - Duration::from_secs(60),
+            &metrics,
+        )
+        .await
+        .unwrap();
+        assert!(is_cached(&rinha, &recent));
+        assert!(!is_cached(&rinha, &old));
+        assert!(!is_cached(&rinha, &known));
+    }
+}
@@ -0,0 +1,55 @@
+use sqlx::{migrate::Migrator, PgPool};
+
+use crate::utils::{env::EnvironmentValues, telemetry};
+
+/// The migrations at `rinha_grpc_server/migrations`, embedded into the binary.
+static MIGRATOR: Migrator = sqlx::migrate!();
+
+/// Applies the pending migrations, the `migrate` subcommand.
+pub async fn migrate() -> Result<(), Box<dyn std::error::Error>> {
+    let env_values = EnvironmentValues::init();
+    if env_values.logger.is_some() {
+        telemetry::init();
+    }
+    let db = PgPool::connect(&env_values.database_url).await?;
+    MIGRATOR.run(&db).await?;
+    tracing::info!(
+        message = "Migrated the database.",
+        version = expected_version()
+    );
+    Ok(())
+}
+
+/// Migrates the database when `DATABASE_MIGRATE_ON_STARTUP` is set, otherwise
+/// refuses to start unless its schema is the one this binary expects.
+pub(crate) async fn prepare(
+    db: &PgPool,
+    env_values: &EnvironmentValues,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if env_values.database_migrate_on_startup {
+        MIGRATOR.run(db).await?;
+    }
+    let version = sqlx::query_as::<_, (Option<i64>,)>(
+        "SELECT MAX(version) FROM _sqlx_migrations WHERE success;",
+    )
+    .fetch_one(db)
+    .await
+    .map(|(version,)| version)
+    .unwrap_or(None);
+    if version != Some(expected_version()) {
+        return Err(format!(
+            "Database schema version is {version:?} while {} is expected, run the `migrate` subcommand",
+            expected_version()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn expected_version() -> i64 {
+    MIGRATOR
+        .iter()
+        .map(|migration| migration.version)
+        .max()
+        .unwrap_or(0)
+}
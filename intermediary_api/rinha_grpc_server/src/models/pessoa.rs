@@ -1,39 +1,293 @@
-use crate::rinha::CreatePessoaRequest;
-use chrono::NaiveDate;
-use serde::{Deserialize, Serialize};
-use sqlx::{postgres::PgRow, FromRow, Row};
-use uuid::Uuid;
+use crate::{
+    pool::{NamedPool, PoolLimit},
+    query_monitor::{explain_analyze, QueryKind, QueryMonitor},
+    rinha::{CreatePessoaRequest, PessoaSearchRequest, SearchMode},
+};
+use chrono::{DateTime, NaiveDate, TimeZone, Utc};
+use serde::{ser::SerializeStruct, Deserialize, Serialize, Serializer};
+use sqlx::{postgres::PgRow, Connection, FromRow, PgConnection, Postgres, QueryBuilder, Row};
+use std::sync::Arc;
+use uuid::{Builder, Uuid};
 
 impl CreatePessoaRequest {
+    /// Returns the parsed `nascimento` of a valid request.
     #[inline(always)]
-    pub fn validate(&self) -> bool {
-        self.apelido.len() <= 32
+    pub fn validate(&self) -> Option<NaiveDate> {
+        if self.apelido.len() <= 32
             && self.nome.len() <= 100
-            && NaiveDate::parse_from_str(&self.nascimento, "%Y-%m-%d").is_ok()
             && self.stack.iter().all(|s| s.len() < 32)
+        {
+            NaiveDate::parse_from_str(&self.nascimento, "%Y-%m-%d").ok()
+        } else {
+            None
+        }
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Maximum amount of pessoas returned by a search.
+pub const PESSOA_SEARCH_LIMIT: usize = 50;
+
+#[derive(Deserialize, Debug, Clone)]
 pub struct Pessoa {
-    pub id: String,
+    pub id: Uuid,
     pub apelido: String,
     pub nome: String,
-    pub nascimento: String,
+    /// Serialized as `%Y-%m-%d`.
+    pub nascimento: NaiveDate,
     pub stack: Option<Vec<String>>,
 }
 
 impl Pessoa {
     #[inline]
     pub fn from(value: CreatePessoaRequest) -> Option<Self> {
-        value.validate().then_some(Pessoa {
-            id: Uuid::new_v4().to_string(),
+        let nascimento = value.validate()?;
+        Some(Pessoa {
+            // Time ordered, so new ids are appended to the end of the id index.
+            id: Uuid::now_v7(),
             apelido: value.apelido,
             nome: value.nome,
-            nascimento: value.nascimento,
+            nascimento,
             stack: Some(value.stack),
         })
     }
+
+    /// The creation time held by the id to the millisecond, mirrors the
+    /// `PESSOA_CREATED_AT` function. `None` for the UUIDv4 of older pessoas.
+    pub fn created_at(&self) -> Option<DateTime<Utc>> {
+        let (secs, nanos) = self.id.get_timestamp()?.to_unix();
+        Utc.timestamp_opt(secs as i64, nanos).single()
+    }
+
+    /// The text matched by searches, mirrors the `BUSCA_TRGM` generated column.
+    #[cfg(not(feature = "without_cache_and_batch"))]
+    pub fn busca(&self) -> String {
+        let mut busca = format!("{}{}", self.nome, self.apelido);
+        if let Some(stack) = self.stack.as_ref() {
+            busca.push_str(&stack.join(" "));
+        }
+        busca.to_lowercase()
+    }
+}
+
+/// Along with its `created_at`, which is left out when the id does not hold
+/// it. Deserializing ignores it, as the id already holds it.
+impl Serialize for Pessoa {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let created_at = self.created_at();
+        let mut pessoa = serializer.serialize_struct("Pessoa", 6)?;
+        pessoa.serialize_field("id", &self.id)?;
+        pessoa.serialize_field("apelido", &self.apelido)?;
+        pessoa.serialize_field("nome", &self.nome)?;
+        pessoa.serialize_field("nascimento", &self.nascimento)?;
+        pessoa.serialize_field("stack", &self.stack)?;
+        match created_at {
+            Some(created_at) => pessoa.serialize_field("created_at", &created_at)?,
+            None => pessoa.skip_field("created_at")?,
+        }
+        pessoa.end()
+    }
+}
+
+/// A search result along with its similarity to the term, when requested.
+#[derive(Serialize)]
+struct ScoredPessoa {
+    #[serde(flatten)]
+    pessoa: Pessoa,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    score: Option<f32>,
+}
+
+impl FromRow<'_, PgRow> for ScoredPessoa {
+    fn from_row(row: &'_ PgRow) -> Result<Self, sqlx::Error> {
+        Ok(Self {
+            pessoa: Pessoa::from_row(row)?,
+            score: row.try_get("score")?,
+        })
+    }
+}
+
+const SELECT_PESSOA_BY_ID: &str = "SELECT * FROM pessoas where id = $1;";
+const SEARCH_PESSOAS: &str =
+    "SELECT id, apelido, nome, nascimento, stack FROM pessoas p where p.busca_trgm LIKE $1 LIMIT $2;";
+pub const COUNT_PESSOAS: &str = "SELECT COUNT(id) FROM pessoas;";
+
+pub async fn pessoa_by_id(
+    db: &NamedPool,
+    id: Uuid,
+    queries: &QueryMonitor,
+) -> Result<Option<Pessoa>, sqlx::Error> {
+    let query = async {
+        let mut conn = queries.acquire(db).await?;
+        sqlx::query_as::<_, Pessoa>(SELECT_PESSOA_BY_ID)
+            .persistent(true)
+            .bind(id)
+            .fetch_optional(&mut *conn)
+            .await
+    };
+    let term = id.to_string();
+    queries
+        .run(
+            QueryKind::PessoaById,
+            SELECT_PESSOA_BY_ID,
+            Some(&term),
+            query,
+        )
+        .await
+}
+
+/// Searches the pessoas whose `busca` contains the term, in no particular order.
+pub async fn search_pessoas(
+    db: &NamedPool,
+    term: &str,
+    queries: &QueryMonitor,
+) -> Result<Vec<Pessoa>, sqlx::Error> {
+    let term_param = format!("%{}%", term);
+    let explain = {
+        let (db, term_param, pool_limit) = (db.clone(), term_param.clone(), queries.pool_limit());
+        move || async move {
+            explain_analyze(&pool_limit, &db, SEARCH_PESSOAS, |query| {
+                query.bind(term_param).bind(PESSOA_SEARCH_LIMIT as i64)
+            })
+            .await
+        }
+    };
+    let query = async {
+        let mut conn = queries.acquire(db).await?;
+        sqlx::query_as::<_, Pessoa>(SEARCH_PESSOAS)
+            .bind(&term_param)
+            .bind(PESSOA_SEARCH_LIMIT as i64)
+            .persistent(true)
+            .fetch_all(&mut *conn)
+            .await
+    };
+    queries
+        .run_explained(
+            QueryKind::PessoaSearch,
+            SEARCH_PESSOAS,
+            Some(term),
+            query,
+            Some(explain),
+        )
+        .await
+}
+
+/// Answers at the database the searches that neither the search index nor the
+/// caches know about, those with a `stack` or `desde` filter or ranked by
+/// similarity, returning the json of the found pessoas.
+pub async fn search_filtered(
+    db: &NamedPool,
+    request: &PessoaSearchRequest,
+    default_similarity_threshold: f32,
+    queries: &QueryMonitor,
+) -> Result<String, sqlx::Error> {
+    let explain = {
+        let (db, request, pool_limit) = (db.clone(), request.clone(), queries.pool_limit());
+        move || explain_filtered(pool_limit, db, request, default_similarity_threshold)
+    };
+    let rows = queries
+        .run_explained(
+            QueryKind::PessoaSearch,
+            &filtered_query(request, false).into_sql(),
+            Some(&request.term),
+            async {
+                let mut conn = queries.acquire(db).await?;
+                fetch_filtered(&mut conn, request, default_similarity_threshold, false).await
+            },
+            Some(explain),
+        )
+        .await?;
+    let pessoas = rows
+        .iter()
+        .map(ScoredPessoa::from_row)
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(serde_json::to_string(&pessoas).unwrap())
+}
+
+async fn explain_filtered(
+    pool_limit: Arc<PoolLimit>,
+    db: NamedPool,
+    request: PessoaSearchRequest,
+    default_similarity_threshold: f32,
+) -> Result<String, sqlx::Error> {
+    let mut conn = pool_limit.acquire(&db).await?;
+    let rows = fetch_filtered(&mut conn, &request, default_similarity_threshold, true).await?;
+    let lines = rows
+        .iter()
+        .map(|row| row.try_get::<String, _>(0))
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(lines.join("\n"))
+}
+
+async fn fetch_filtered(
+    conn: &mut PgConnection,
+    request: &PessoaSearchRequest,
+    default_similarity_threshold: f32,
+    explain: bool,
+) -> Result<Vec<PgRow>, sqlx::Error> {
+    let mut tx = conn.begin().await?;
+    if request.mode() == SearchMode::Similarity {
+        let threshold = request
+            .similarity_threshold
+            .unwrap_or(default_similarity_threshold);
+        sqlx::query("SELECT set_config('pg_trgm.word_similarity_threshold', $1, true);")
+            .bind(threshold.to_string())
+            .execute(&mut *tx)
+            .await?;
+    }
+    let rows = filtered_query(request, explain)
+        .build()
+        .fetch_all(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(rows)
+}
+
+fn filtered_query(request: &PessoaSearchRequest, explain: bool) -> QueryBuilder<'static, Postgres> {
+    let ranked = request.mode() == SearchMode::Similarity;
+    let term = request.term.to_lowercase();
+    let mut query = QueryBuilder::new(if explain { "EXPLAIN (ANALYZE) " } else { "" });
+    query.push("SELECT id, apelido, nome, nascimento, stack, ");
+    if ranked && request.include_score {
+        query
+            .push("word_similarity(")
+            .push_bind(term.clone())
+            .push(", p.busca_trgm)");
+    } else {
+        query.push("NULL::real");
+    }
+    query.push(" AS score FROM pessoas p WHERE ");
+    // `<%` and `<<->` are answered by the GIST index, the threshold is set for the transaction.
+    if ranked {
+        query.push_bind(term.clone()).push(" <% p.busca_trgm");
+    } else {
+        query
+            .push("p.busca_trgm LIKE ")
+            .push_bind(format!("%{}%", request.term));
+    }
+    if let Some(stack) = request.stack.clone() {
+        query
+            .push(" AND p.stack @> ARRAY[")
+            .push_bind(stack)
+            .push("]");
+    }
+    if let Some(desde) = request.desde {
+        // The lowest UUIDv7 of that millisecond, the ids of the pessoas created since are greater.
+        let first_id = Builder::from_unix_timestamp_millis(desde.max(0) as u64, &[0; 10]);
+        query
+            .push(" AND p.id >= ")
+            .push_bind(first_id.into_uuid())
+            .push(" AND pessoa_created_at(p.id) IS NOT NULL");
+    }
+    if ranked {
+        query
+            .push(" ORDER BY ")
+            .push_bind(term)
+            .push(" <<-> p.busca_trgm");
+    } else if request.desde.is_some() {
+        query.push(" ORDER BY p.id");
+    }
+    query.push(" LIMIT ").push_bind(PESSOA_SEARCH_LIMIT as i64);
+    query
 }
 
 impl FromRow<'_, PgRow> for Pessoa {
@@ -43,9 +297,34 @@ impl FromRow<'_, PgRow> for Pessoa {
             nome: row.try_get("nome")?,
             apelido: row.try_get("apelido")?,
             nascimento: row.try_get("nascimento")?,
-            stack: row
-                .try_get::<Option<String>, &'_ str>("stack")?
-                .map(|stacks| stacks.split(' ').map(|s| s.into()).collect()),
+            stack: row.try_get("stack")?,
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pessoa(id: Uuid) -> Pessoa {
+        Pessoa {
+            id,
+            apelido: "zé".to_owned(),
+            nome: "José".to_owned(),
+            nascimento: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            stack: None,
+        }
+    }
+
+    #[test]
+    fn serializes_the_creation_time_of_the_id() {
+        let id = Uuid::parse_str("018b4a6a-6f2c-7000-8000-000000000000").unwrap();
+        let json = serde_json::to_value(pessoa(id)).unwrap();
+        assert_eq!(json["created_at"], "2023-10-20T00:09:31.180Z");
+        let read: Pessoa = serde_json::from_value(json).unwrap();
+        assert_eq!(read.id, id);
+
+        let json = serde_json::to_value(pessoa(Uuid::new_v4())).unwrap();
+        assert!(json.get("created_at").is_none());
+    }
+}
@@ -0,0 +1,356 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use opentelemetry::metrics::Counter;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    event_sink::{Event, EventSink, SinkError},
+    utils::env::OutboxConfig,
+};
+
+/// Only the events of the transactions finished before the oldest running one are read,
+/// see the `0007_create_outbox` migration.
+const SELECT_EVENTS: &str = "SELECT txid::text, seq, kind, payload::text, created_at FROM pessoa_events WHERE (txid, seq) > ($1::xid8, $2) AND txid < pg_snapshot_xmin(pg_current_snapshot()) ORDER BY txid, seq LIMIT $3;";
+
+struct OutboxMetrics {
+    delivered: Counter<u64>,
+    failures: Counter<u64>,
+}
+
+impl OutboxMetrics {
+    fn new() -> Self {
+        let meter = opentelemetry::global::meter("rinha_grpc_server");
+        Self {
+            delivered: meter
+                .u64_counter("outbox.delivered")
+                .with_description("Events delivered to the sink")
+                .init(),
+            failures: meter
+                .u64_counter("outbox.failures")
+                .with_description("Deliveries to the sink that failed and are going to be retried")
+                .init(),
+        }
+    }
+}
+
+/// How long an instance holds the cursor of a sink while delivering to it,
+/// longer than the timeouts of the sinks. Another instance takes it over once
+/// it expires, so a crashed one does not stall the sink.
+const LEASE: Duration = Duration::from_secs(60);
+
+/// Position at the outbox, the events after it are yet to be delivered.
+struct Cursor {
+    txid: String,
+    seq: i64,
+}
+
+/// Relays the events of the outbox to the sink, with at least once delivery.
+///
+/// The cursor of the sink is leased while a batch of events is delivered and
+/// only moved past them once it succeeds, so a failure or a crash in between
+/// delivers them again. The lease also leaves a single instance relaying to
+/// the sink at a time, the other ones skip it and try again later. No
+/// transaction is held open meanwhile, and the events every cursor moved past
+/// are deleted.
+pub async fn relay_task(db: PgPool, sink: Box<dyn EventSink>, config: OutboxConfig) {
+    let metrics = OutboxMetrics::new();
+    let name = sink.name();
+    let (mut failures, mut cursor_created) = (0, false);
+    loop {
+        if !cursor_created {
+            cursor_created = create_cursor(&db, &name).await.is_ok();
+        }
+        let relayed = match cursor_created {
+            true => relay(&db, sink.as_ref(), &name, config.batch_size).await,
+            false => Err("Failed to create the cursor of the sink.".into()),
+        };
+        match relayed {
+            Ok(delivered) => {
+                failures = 0;
+                metrics.delivered.add(delivered, &[]);
+                if delivered < config.batch_size as u64 {
+                    tokio::time::sleep(config.poll_interval).await;
+                }
+            }
+            Err(err) => {
+                let delay = config.retry_base_delay * 2u32.pow(failures.min(16));
+                tracing::warn!(
+                    message = "Failed to relay the outbox events.",
+                    sink = name,
+                    %err,
+                    ?delay
+                );
+                metrics.failures.add(1, &[]);
+                tokio::time::sleep(delay.min(config.retry_max_delay)).await;
+                failures += 1;
+            }
+        }
+    }
+}
+
+/// Starts a sink at the beginning of the outbox, unless it already has a cursor.
+async fn create_cursor(db: &PgPool, name: &str) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO outbox_cursors (sink) VALUES ($1) ON CONFLICT DO NOTHING;")
+        .bind(name)
+        .execute(db)
+        .await
+        .map(|_| ())
+}
+
+/// Delivers the next batch of events, returning its size.
+pub(crate) async fn relay(
+    db: &PgPool,
+    sink: &dyn EventSink,
+    name: &str,
+    batch_size: i64,
+) -> Result<u64, SinkError> {
+    let lease = Uuid::now_v7();
+    let Some(cursor) = lease_cursor(db, name, lease).await? else {
+        // Another instance is relaying to the sink.
+        return Ok(0);
+    };
+    let (delivered, last) = match deliver_next(db, sink, &cursor, batch_size).await {
+        Ok((delivered, Some(last))) => (delivered, last),
+        res => {
+            release_cursor(db, name, lease).await;
+            return res.map(|(delivered, _)| delivered);
+        }
+    };
+    // A lease that expired meanwhile was taken over, the events are delivered again from the cursor.
+    let moved = sqlx::query(
+        "UPDATE outbox_cursors SET txid = $3::xid8, seq = $4, leased_by = NULL, leased_until = '-infinity' WHERE sink = $1 AND leased_by = $2;",
+    )
+    .bind(name)
+    .bind(lease)
+    .bind(last.txid)
+    .bind(last.seq)
+    .execute(db)
+    .await?;
+    if moved.rows_affected() > 0 {
+        if let Err(err) = prune(db).await {
+            tracing::warn!(message = "Failed to prune the delivered outbox events.", %err);
+        }
+    }
+    Ok(delivered)
+}
+
+/// Delivers the events after the cursor, returning their amount and the position of the last one.
+async fn deliver_next(
+    db: &PgPool,
+    sink: &dyn EventSink,
+    cursor: &Cursor,
+    batch_size: i64,
+) -> Result<(u64, Option<Cursor>), SinkError> {
+    let rows = sqlx::query_as::<_, (String, i64, String, String, DateTime<Utc>)>(SELECT_EVENTS)
+        .bind(&cursor.txid)
+        .bind(cursor.seq)
+        .bind(batch_size)
+        .fetch_all(db)
+        .await?;
+    let Some((txid, seq, ..)) = rows.last().cloned() else {
+        return Ok((0, None));
+    };
+    let events = rows
+        .into_iter()
+        .map(|(_, seq, kind, payload, created_at)| {
+            Ok(Event {
+                seq,
+                kind,
+                created_at,
+                data: serde_json::from_str(&payload)?,
+            })
+        })
+        .collect::<Result<Vec<_>, serde_json::Error>>()?;
+    sink.deliver(&events).await?;
+    Ok((events.len() as u64, Some(Cursor { txid, seq })))
+}
+
+/// The cursor of the sink, unless another instance holds its lease.
+async fn lease_cursor(db: &PgPool, name: &str, lease: Uuid) -> Result<Option<Cursor>, sqlx::Error> {
+    let cursor = sqlx::query_as::<_, (String, i64)>(
+        "UPDATE outbox_cursors SET leased_by = $2, leased_until = NOW() + $3 WHERE sink = $1 AND leased_until < NOW() RETURNING txid::text, seq;",
+    )
+    .bind(name)
+    .bind(lease)
+    .bind(LEASE)
+    .fetch_optional(db)
+    .await?;
+    Ok(cursor.map(|(txid, seq)| Cursor { txid, seq }))
+}
+
+/// Gives the lease up before it expires, so the next attempt need not wait for it.
+async fn release_cursor(db: &PgPool, name: &str, lease: Uuid) {
+    let released = sqlx::query(
+        "UPDATE outbox_cursors SET leased_by = NULL, leased_until = '-infinity' WHERE sink = $1 AND leased_by = $2;",
+    )
+    .bind(name)
+    .bind(lease)
+    .execute(db)
+    .await;
+    if let Err(err) = released {
+        tracing::warn!(message = "Failed to release the outbox cursor.", sink = name, %err);
+    }
+}
+
+/// Deletes the events behind every cursor, those of the disabled webhook
+/// subscriptions included, since they resume from theirs once enabled again.
+async fn prune(db: &PgPool) -> Result<u64, sqlx::Error> {
+    sqlx::query(
+        "DELETE FROM pessoa_events WHERE (txid, seq) <= (SELECT txid, seq FROM outbox_cursors ORDER BY txid, seq LIMIT 1);",
+    )
+    .execute(db)
+    .await
+    .map(|res| res.rows_affected())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use super::*;
+
+    /// Records the delivered events, optionally taking the lease of the cursor
+    /// over while delivering them, as an instance would once it expires.
+    struct RecordingSink {
+        name: &'static str,
+        delivered: Mutex<Vec<i64>>,
+        take_over: Option<PgPool>,
+    }
+
+    impl RecordingSink {
+        fn new(name: &'static str) -> Self {
+            Self {
+                name,
+                delivered: Mutex::new(Vec::new()),
+                take_over: None,
+            }
+        }
+
+        fn delivered(&self) -> Vec<i64> {
+            self.delivered.lock().unwrap().clone()
+        }
+    }
+
+    #[tonic::async_trait]
+    impl EventSink for RecordingSink {
+        fn name(&self) -> String {
+            self.name.into()
+        }
+
+        async fn deliver(&self, events: &[Event]) -> Result<(), SinkError> {
+            if let Some(db) = self.take_over.as_ref() {
+                expire_lease(db, self.name).await;
+                lease_cursor(db, self.name, Uuid::now_v7()).await?;
+            }
+            let mut delivered = self.delivered.lock().unwrap();
+            delivered.extend(events.iter().map(|event| event.seq));
+            Ok(())
+        }
+    }
+
+    async fn insert_events(db: &PgPool, amount: usize) {
+        for _ in 0..amount {
+            sqlx::query(
+                "INSERT INTO pessoa_events (kind, payload) VALUES ('pessoa.created', '{}');",
+            )
+            .execute(db)
+            .await
+            .unwrap();
+        }
+    }
+
+    async fn events(db: &PgPool) -> Vec<i64> {
+        sqlx::query_scalar("SELECT seq FROM pessoa_events ORDER BY seq;")
+            .fetch_all(db)
+            .await
+            .unwrap()
+    }
+
+    async fn cursor(db: &PgPool, name: &str) -> (i64, Option<Uuid>) {
+        sqlx::query_as("SELECT seq, leased_by FROM outbox_cursors WHERE sink = $1;")
+            .bind(name)
+            .fetch_one(db)
+            .await
+            .unwrap()
+    }
+
+    async fn expire_lease(db: &PgPool, name: &str) {
+        sqlx::query(
+            "UPDATE outbox_cursors SET leased_until = NOW() - INTERVAL '1 second' WHERE sink = $1;",
+        )
+        .bind(name)
+        .execute(db)
+        .await
+        .unwrap();
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    async fn moves_the_cursor_past_the_delivered_events(db: PgPool) {
+        let sink = RecordingSink::new("a");
+        create_cursor(&db, "a").await.unwrap();
+        insert_events(&db, 3).await;
+        assert_eq!(relay(&db, &sink, "a", 2).await.unwrap(), 2);
+        assert_eq!(cursor(&db, "a").await, (2, None));
+        assert_eq!(relay(&db, &sink, "a", 2).await.unwrap(), 1);
+        assert_eq!(relay(&db, &sink, "a", 2).await.unwrap(), 0);
+        assert_eq!(cursor(&db, "a").await, (3, None));
+        assert_eq!(sink.delivered(), [1, 2, 3]);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    async fn skips_a_cursor_leased_by_another_instance_until_the_lease_expires(db: PgPool) {
+        let sink = RecordingSink::new("a");
+        create_cursor(&db, "a").await.unwrap();
+        insert_events(&db, 1).await;
+        let other = Uuid::now_v7();
+        assert!(lease_cursor(&db, "a", other).await.unwrap().is_some());
+        assert_eq!(relay(&db, &sink, "a", 10).await.unwrap(), 0);
+        assert_eq!(cursor(&db, "a").await, (0, Some(other)));
+
+        // The other instance crashed.
+        expire_lease(&db, "a").await;
+        assert_eq!(relay(&db, &sink, "a", 10).await.unwrap(), 1);
+        assert_eq!(cursor(&db, "a").await, (1, None));
+        assert_eq!(sink.delivered(), [1]);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    async fn delivers_again_the_events_of_a_lease_taken_over(db: PgPool) {
+        let mut sink = RecordingSink::new("a");
+        sink.take_over = Some(db.clone());
+        create_cursor(&db, "a").await.unwrap();
+        insert_events(&db, 1).await;
+        // The lease expired while delivering, so the cursor is left to the new holder.
+        relay(&db, &sink, "a", 10).await.unwrap();
+        let (seq, leased_by) = cursor(&db, "a").await;
+        assert_eq!(seq, 0);
+        assert!(leased_by.is_some());
+        assert_eq!(events(&db).await, [1]);
+
+        sink.take_over = None;
+        expire_lease(&db, "a").await;
+        assert_eq!(relay(&db, &sink, "a", 10).await.unwrap(), 1);
+        assert_eq!(cursor(&db, "a").await, (1, None));
+        assert_eq!(sink.delivered(), [1, 1]);
+    }
+
+    #[sqlx::test]
+    #[ignore = "needs a database at DATABASE_URL"]
+    async fn prunes_the_events_up_to_the_slowest_cursor(db: PgPool) {
+        let (a, b) = (RecordingSink::new("a"), RecordingSink::new("b"));
+        create_cursor(&db, "a").await.unwrap();
+        create_cursor(&db, "b").await.unwrap();
+        insert_events(&db, 3).await;
+        assert_eq!(relay(&db, &a, "a", 10).await.unwrap(), 3);
+        assert_eq!(events(&db).await, [1, 2, 3]);
+        assert_eq!(relay(&db, &b, "b", 2).await.unwrap(), 2);
+        assert_eq!(events(&db).await, [3]);
+        assert_eq!(relay(&db, &b, "b", 2).await.unwrap(), 1);
+        assert!(events(&db).await.is_empty());
+    }
+}
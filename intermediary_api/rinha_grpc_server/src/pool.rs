@@ -0,0 +1,374 @@
+use std::{
+    collections::HashMap,
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use opentelemetry::{
+    metrics::{Histogram, ObservableGauge, Unit},
+    KeyValue,
+};
+use sqlx::{
+    pool::PoolConnection,
+    postgres::{PgConnectOptions, PgPoolOptions, Postgres},
+    PgConnection, PgPool,
+};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::utils::env::PoolConfig;
+
+/// Name of the pool to the primary database.
+pub const PRIMARY: &str = "primary";
+
+/// A pool along with the name its [`PoolLimit`] and metrics are kept under.
+#[derive(Clone)]
+pub struct NamedPool {
+    pub name: String,
+    pub pool: PgPool,
+}
+
+/// Options of the pools to the primary database and to the read replicas.
+pub fn options(config: &PoolConfig) -> PgPoolOptions {
+    PgPoolOptions::new()
+        .max_connections(config.max_size)
+        .min_connections(config.min_size)
+        .idle_timeout(config.idle_timeout)
+        .acquire_timeout(config.acquire_timeout)
+}
+
+/// Connects to the primary database, refusing a pool that would take more
+/// connections than it accepts.
+pub async fn connect(
+    connect_options: PgConnectOptions,
+    config: &PoolConfig,
+) -> Result<PgPool, Box<dyn std::error::Error>> {
+    let db = options(config).connect_with(connect_options).await?;
+    check_capacity(PRIMARY, capacity(&db).await?, config)?;
+    Ok(db)
+}
+
+/// The `max_connections` of the database and its `superuser_reserved_connections`.
+pub async fn capacity(db: &PgPool) -> Result<(i32, i32), sqlx::Error> {
+    sqlx::query_as::<_, (i32, i32)>(
+        "SELECT current_setting('max_connections')::int, current_setting('superuser_reserved_connections')::int;",
+    )
+    .fetch_one(db)
+    .await
+}
+
+/// Every instance may open up to `max_size` connections to each database,
+/// which have to fit into its `max_connections` minus the ones reserved to
+/// the superusers. Otherwise an instance would fail to connect once the
+/// others filled up their pools.
+pub fn check_capacity(
+    name: &str,
+    (max_connections, reserved): (i32, i32),
+    config: &PoolConfig,
+) -> Result<(), String> {
+    let available = (max_connections - reserved).max(0) as u32;
+    let wanted = config.max_size.saturating_mul(config.instances);
+    if wanted > available {
+        return Err(format!(
+            "DATABASE_POOL_MAX_SIZE of {} for {} instance(s) takes {wanted} connections while the {name} database accepts \
+             {available} (max_connections of {max_connections} minus {reserved} reserved)",
+            config.max_size, config.instances
+        ));
+    }
+    tracing::info!(
+        message = "Checked the database capacity.",
+        pool = name,
+        wanted,
+        available
+    );
+    Ok(())
+}
+
+/// A pooled connection holding one of the permits of its pool at the [`PoolLimit`].
+pub struct LimitedConnection {
+    connection: PoolConnection<Postgres>,
+    permit: Option<OwnedSemaphorePermit>,
+    limit: Arc<Limit>,
+}
+
+impl Deref for LimitedConnection {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.connection
+    }
+}
+
+impl DerefMut for LimitedConnection {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.connection
+    }
+}
+
+impl Drop for LimitedConnection {
+    fn drop(&mut self) {
+        if let Some(permit) = self.permit.take() {
+            if self.limit.pay_deficit() {
+                permit.forget();
+            }
+        }
+    }
+}
+
+/// Limit of one of the pools.
+struct Limit {
+    pool: NamedPool,
+    permits: Arc<Semaphore>,
+    limit: Mutex<u32>,
+    /// Permits to forget as they are given back, since they were in use when the limit was lowered.
+    deficit: AtomicU32,
+}
+
+impl Limit {
+    /// Whether a permit being given back went to the deficit instead.
+    fn pay_deficit(&self) -> bool {
+        self.deficit
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |deficit| {
+                deficit.checked_sub(1)
+            })
+            .is_ok()
+    }
+
+    fn set(&self, limit: u32) -> u32 {
+        let mut current = self.limit.lock().unwrap();
+        let previous = *current;
+        if limit > previous {
+            let raised = limit - previous;
+            let deficit = self
+                .deficit
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |deficit| {
+                    Some(deficit - deficit.min(raised))
+                })
+                .unwrap_or_default();
+            self.permits
+                .add_permits((raised - deficit.min(raised)) as usize);
+        } else {
+            let mut lowered = previous - limit;
+            while lowered > 0 {
+                let available = (self.permits.available_permits() as u32).min(lowered);
+                if available == 0 {
+                    break;
+                }
+                if let Ok(permits) = self.permits.try_acquire_many(available) {
+                    permits.forget();
+                    lowered -= available;
+                }
+            }
+            self.deficit.fetch_add(lowered, Ordering::AcqRel);
+        }
+        *current = limit;
+        previous
+    }
+}
+
+/// Bounds the connections taken by the reads and the batch inserts from each
+/// pool below its size, so it can be lowered at runtime, and measures the
+/// time they wait for one.
+///
+/// The pools themselves can't be resized, so the limits are only ever lowered
+/// from `DATABASE_POOL_MAX_SIZE`. The background tasks, like the listener and
+/// the outbox relay, take their connections straight from the pool.
+pub struct PoolLimit {
+    /// By the name of their pool.
+    limits: HashMap<String, Arc<Limit>>,
+    max_size: u32,
+    acquire_timeout: Duration,
+    wait: Histogram<f64>,
+    _limit: ObservableGauge<u64>,
+    _connections: ObservableGauge<u64>,
+}
+
+impl PoolLimit {
+    /// Limits each of the `pools`, which are observed by the gauges under their names.
+    pub fn new(config: &PoolConfig, pools: Vec<NamedPool>) -> Self {
+        let meter = opentelemetry::global::meter("rinha_grpc_server");
+        let limits: HashMap<String, Arc<Limit>> = pools
+            .into_iter()
+            .map(|pool| {
+                let limit = Arc::new(Limit {
+                    pool: pool.clone(),
+                    permits: Arc::new(Semaphore::new(config.max_size as usize)),
+                    limit: Mutex::new(config.max_size),
+                    deficit: AtomicU32::new(0),
+                });
+                (pool.name, limit)
+            })
+            .collect();
+        let observed_limits: Vec<Arc<Limit>> = limits.values().cloned().collect();
+        let observed_pools = observed_limits.clone();
+        Self {
+            max_size: config.max_size,
+            acquire_timeout: config.acquire_timeout,
+            wait: meter
+                .f64_histogram("db.pool.wait")
+                .with_description("Time waited for a connection by the reads and the batch inserts")
+                .with_unit(Unit::new("ms"))
+                .init(),
+            _limit: meter
+                .u64_observable_gauge("db.pool.limit")
+                .with_description("Connections the reads and the batch inserts may take at once")
+                .with_callback(move |gauge| {
+                    for limit in observed_limits.iter() {
+                        gauge.observe(
+                            *limit.limit.lock().unwrap() as u64,
+                            &[KeyValue::new("pool", limit.pool.name.clone())],
+                        )
+                    }
+                })
+                .init(),
+            _connections: meter
+                .u64_observable_gauge("db.pool.connections")
+                .with_description("Open connections of the pool, either idle or in use")
+                .with_callback(move |gauge| {
+                    for limit in observed_pools.iter() {
+                        let idle = limit.pool.pool.num_idle() as u64;
+                        let size = limit.pool.pool.size() as u64;
+                        for (state, amount) in
+                            [("idle", idle), ("in_use", size.saturating_sub(idle))]
+                        {
+                            gauge.observe(
+                                amount,
+                                &[
+                                    KeyValue::new("pool", limit.pool.name.clone()),
+                                    KeyValue::new("state", state),
+                                ],
+                            );
+                        }
+                    }
+                })
+                .init(),
+            limits,
+        }
+    }
+
+    /// Waits up to `DATABASE_POOL_ACQUIRE_TIMEOUT_MS` for both a permit and a
+    /// connection of `db`, failing when it is not one of the pools it was
+    /// created with.
+    pub async fn acquire(&self, db: &NamedPool) -> Result<LimitedConnection, sqlx::Error> {
+        let limit = self.limits.get(&db.name).ok_or_else(|| {
+            sqlx::Error::Configuration(format!("No limit for the {} pool", db.name).into())
+        })?;
+        let started = Instant::now();
+        let acquired = async {
+            let permit = limit
+                .permits
+                .clone()
+                .acquire_owned()
+                .await
+                .map_err(|_| sqlx::Error::PoolClosed)?;
+            let connection = db.pool.acquire().await?;
+            Ok::<_, sqlx::Error>((permit, connection))
+        };
+        let (permit, connection) = tokio::time::timeout(self.acquire_timeout, acquired)
+            .await
+            .map_err(|_| sqlx::Error::PoolTimedOut)??;
+        self.wait.record(
+            started.elapsed().as_secs_f64() * 1000.0,
+            &[KeyValue::new("pool", db.name.clone())],
+        );
+        Ok(LimitedConnection {
+            connection,
+            permit: Some(permit),
+            limit: limit.clone(),
+        })
+    }
+
+    /// Sets the limit of every pool, up to their size, returning the previous one.
+    ///
+    /// A lower limit takes effect as the connections in use are given back.
+    pub fn set_limit(&self, limit: u32) -> Result<u32, String> {
+        if limit == 0 || limit > self.max_size {
+            return Err(format!(
+                "The limit must be between 1 and DATABASE_POOL_MAX_SIZE of {}",
+                self.max_size
+            ));
+        }
+        let mut previous = limit;
+        for pool_limit in self.limits.values() {
+            previous = pool_limit.set(limit);
+        }
+        Ok(previous)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(name: &str) -> NamedPool {
+        NamedPool {
+            name: name.to_owned(),
+            pool: PgPool::connect_lazy("postgres://localhost/rinha").unwrap(),
+        }
+    }
+
+    fn give_back(limit: &Limit, permit: OwnedSemaphorePermit) {
+        if limit.pay_deficit() {
+            permit.forget();
+        }
+    }
+
+    #[tokio::test]
+    async fn lowers_the_limit_as_the_permits_are_given_back() {
+        let limit = Limit {
+            pool: named(PRIMARY),
+            permits: Arc::new(Semaphore::new(4)),
+            limit: Mutex::new(4),
+            deficit: AtomicU32::new(0),
+        };
+        let acquire = || limit.permits.clone().try_acquire_owned().unwrap();
+        let in_use = [acquire(), acquire(), acquire()];
+        assert_eq!(limit.set(2), 4);
+        assert_eq!(limit.permits.available_permits(), 0);
+        for permit in in_use {
+            give_back(&limit, permit);
+        }
+        assert_eq!(limit.permits.available_permits(), 2);
+
+        let in_use = [acquire(), acquire()];
+        assert_eq!(limit.set(1), 2);
+        assert_eq!(limit.set(3), 1);
+        assert_eq!(limit.permits.available_permits(), 1);
+        for permit in in_use {
+            give_back(&limit, permit);
+        }
+        assert_eq!(limit.permits.available_permits(), 3);
+        assert_eq!(limit.deficit.load(Ordering::Acquire), 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_pool_without_a_limit() {
+        let config = PoolConfig {
+            max_size: 4,
+            min_size: 0,
+            idle_timeout: None,
+            acquire_timeout: Duration::from_millis(10),
+            instances: 1,
+        };
+        let pool_limit = PoolLimit::new(&config, vec![named(PRIMARY)]);
+        let refused = pool_limit.acquire(&named("replica_0")).await;
+        assert!(matches!(refused, Err(sqlx::Error::Configuration(_))));
+    }
+
+    #[test]
+    fn checks_the_capacity_of_each_database() {
+        let config = PoolConfig {
+            max_size: 40,
+            min_size: 0,
+            idle_timeout: None,
+            acquire_timeout: Duration::from_millis(10),
+            instances: 2,
+        };
+        assert!(check_capacity(PRIMARY, (100, 3), &config).is_ok());
+        let refused = check_capacity("replica_0", (80, 3), &config).unwrap_err();
+        assert!(refused.contains("replica_0 database accepts 77"));
+    }
+}
@@ -0,0 +1,279 @@
+use std::{
+    future::Future,
+    io,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use opentelemetry::{
+    metrics::{Counter, Histogram, Unit},
+    KeyValue,
+};
+use sqlx::{postgres::PgArguments, query::QueryAs, Postgres};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt};
+use tonic::Status;
+
+use crate::{
+    pool::{LimitedConnection, NamedPool, PoolLimit},
+    utils::env::{ExplainConfig, QueryConfig},
+};
+
+/// The reads answered at the database, each one with its own timeout.
+#[derive(Clone, Copy)]
+pub enum QueryKind {
+    PessoaById,
+    PessoaSearch,
+    CountPessoa,
+}
+
+impl QueryKind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::PessoaById => "pessoa_by_id",
+            Self::PessoaSearch => "pessoa_search",
+            Self::CountPessoa => "count_pessoa",
+        }
+    }
+}
+
+struct QueryMetrics {
+    duration: Histogram<f64>,
+    slow: Counter<u64>,
+    timeouts: Counter<u64>,
+}
+
+impl QueryMetrics {
+    fn new() -> Self {
+        let meter = opentelemetry::global::meter("rinha_grpc_server");
+        Self {
+            duration: meter
+                .f64_histogram("db.query.duration")
+                .with_description("Time taken by the reads answered at the database")
+                .with_unit(Unit::new("ms"))
+                .init(),
+            slow: meter
+                .u64_counter("db.query.slow")
+                .with_description("Reads taking longer than the slow query threshold")
+                .init(),
+            timeouts: meter
+                .u64_counter("db.query.timeouts")
+                .with_description("Reads given up on after their timeout")
+                .init(),
+        }
+    }
+}
+
+/// Times out the reads, logs the slow ones and captures the plan of a sample
+/// of the slow searches.
+///
+/// A timed out query is only dropped, Postgres keeps running it until it
+/// finds out, so the timeouts bound the latency of the requests rather than
+/// the load of the database.
+pub struct QueryMonitor {
+    config: QueryConfig,
+    metrics: QueryMetrics,
+    pool_limit: Arc<PoolLimit>,
+}
+
+impl QueryMonitor {
+    pub fn new(config: &QueryConfig, pool_limit: Arc<PoolLimit>) -> Self {
+        Self {
+            config: config.clone(),
+            metrics: QueryMetrics::new(),
+            pool_limit,
+        }
+    }
+
+    /// A connection to run a read on, waiting within its timeout when the
+    /// [`PoolLimit`] is reached.
+    pub async fn acquire(&self, db: &NamedPool) -> Result<LimitedConnection, sqlx::Error> {
+        self.pool_limit.acquire(db).await
+    }
+
+    /// For the plans of the slow searches, which take their connections at the background.
+    pub fn pool_limit(&self) -> Arc<PoolLimit> {
+        self.pool_limit.clone()
+    }
+
+    fn timeout(&self, kind: QueryKind) -> Option<Duration> {
+        match kind {
+            QueryKind::PessoaById => self.config.pessoa_by_id_timeout,
+            QueryKind::PessoaSearch => self.config.pessoa_search_timeout,
+            QueryKind::CountPessoa => self.config.count_pessoa_timeout,
+        }
+    }
+
+    pub async fn run<T>(
+        &self,
+        kind: QueryKind,
+        sql: &str,
+        term: Option<&str>,
+        query: impl Future<Output = Result<T, sqlx::Error>>,
+    ) -> Result<T, sqlx::Error> {
+        self.run_explained(kind, sql, term, query, None::<fn() -> NoPlan>)
+            .await
+    }
+
+    /// Runs a search, `explain` returns its `EXPLAIN (ANALYZE)` when it is
+    /// slow and sampled. It is run at the background, once the search answered.
+    pub async fn run_explained<T, E, P>(
+        &self,
+        kind: QueryKind,
+        sql: &str,
+        term: Option<&str>,
+        query: impl Future<Output = Result<T, sqlx::Error>>,
+        explain: Option<E>,
+    ) -> Result<T, sqlx::Error>
+    where
+        E: FnOnce() -> P,
+        P: Future<Output = Result<String, sqlx::Error>> + Send + 'static,
+    {
+        let attributes = [KeyValue::new("query", kind.name())];
+        let started = Instant::now();
+        let res = match self.timeout(kind) {
+            Some(timeout) => match tokio::time::timeout(timeout, query).await {
+                Ok(res) => res,
+                Err(_) => {
+                    self.metrics.timeouts.add(1, &attributes);
+                    tracing::warn!(
+                        message = "Query timed out.",
+                        query = kind.name(),
+                        sql,
+                        term,
+                        ?timeout
+                    );
+                    return Err(timed_out());
+                }
+            },
+            None => query.await,
+        };
+        let elapsed = started.elapsed();
+        self.metrics
+            .duration
+            .record(elapsed.as_secs_f64() * 1000.0, &attributes);
+        if self
+            .config
+            .slow_threshold
+            .is_some_and(|threshold| elapsed > threshold)
+        {
+            self.metrics.slow.add(1, &attributes);
+            tracing::warn!(
+                message = "Slow query.",
+                query = kind.name(),
+                sql,
+                term,
+                duration_ms = elapsed.as_millis() as u64
+            );
+            if let (Some(config), Some(explain)) = (self.config.explain.as_ref(), explain) {
+                if rand::random::<f64>() < config.sample_rate {
+                    let entry = Explained {
+                        query: kind.name(),
+                        sql: sql.to_owned(),
+                        term: term.map(str::to_owned),
+                        elapsed,
+                    };
+                    let plan = explain();
+                    tokio::spawn(write_plan(config.clone(), entry, plan, self.timeout(kind)));
+                }
+            }
+        }
+        res
+    }
+}
+
+type NoPlan = std::future::Ready<Result<String, sqlx::Error>>;
+
+fn timed_out() -> sqlx::Error {
+    sqlx::Error::Io(io::Error::new(
+        io::ErrorKind::TimedOut,
+        "The query timed out",
+    ))
+}
+
+/// The status answered for a read failing at the database, `DEADLINE_EXCEEDED`
+/// when it timed out, either running or waiting for a connection.
+pub fn query_status(err: &sqlx::Error) -> Status {
+    match err {
+        sqlx::Error::PoolTimedOut => {
+            Status::deadline_exceeded("Timed out waiting for a connection")
+        }
+        sqlx::Error::Io(err) if err.kind() == io::ErrorKind::TimedOut => {
+            Status::deadline_exceeded("The query timed out")
+        }
+        err => {
+            tracing::error!(message = "Query failed.", %err);
+            Status::internal("Internal server error")
+        }
+    }
+}
+
+struct Explained {
+    query: &'static str,
+    sql: String,
+    term: Option<String>,
+    elapsed: Duration,
+}
+
+/// The plan is given the timeout of the query it explains, since it runs it again.
+async fn write_plan(
+    config: ExplainConfig,
+    entry: Explained,
+    plan: impl Future<Output = Result<String, sqlx::Error>>,
+    timeout: Option<Duration>,
+) {
+    let plan = match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, plan)
+            .await
+            .unwrap_or_else(|_| Err(timed_out())),
+        None => plan.await,
+    };
+    let plan = match plan {
+        Ok(plan) => plan,
+        Err(err) => {
+            tracing::warn!(message = "Failed to explain a slow query.", %err);
+            return;
+        }
+    };
+    let mut line = serde_json::json!({
+        "at": Utc::now(),
+        "query": entry.query,
+        "sql": entry.sql.trim(),
+        "term": entry.term,
+        "duration_ms": entry.elapsed.as_millis() as u64,
+        "plan": plan,
+    })
+    .to_string();
+    line.push('\n');
+    let written = async {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.file)
+            .await?;
+        file.write_all(line.as_bytes()).await
+    };
+    if let Err(err) = written.await {
+        tracing::warn!(message = "Failed to write the plan of a slow query.", %err);
+    }
+}
+
+/// Runs `EXPLAIN (ANALYZE)` of the query, binding its parameters with `bind`,
+/// returning the lines of the plan.
+pub async fn explain_analyze(
+    pool_limit: &PoolLimit,
+    db: &NamedPool,
+    sql: &str,
+    bind: impl for<'q> FnOnce(ExplainQuery<'q>) -> ExplainQuery<'q>,
+) -> Result<String, sqlx::Error> {
+    let explain = format!("EXPLAIN (ANALYZE) {}", sql.trim());
+    let mut conn = pool_limit.acquire(db).await?;
+    let lines = bind(sqlx::query_as(&explain)).fetch_all(&mut *conn).await?;
+    Ok(lines
+        .into_iter()
+        .map(|(line,)| line)
+        .collect::<Vec<_>>()
+        .join("\n"))
+}
+
+type ExplainQuery<'q> = QueryAs<'q, Postgres, (String,), PgArguments>;
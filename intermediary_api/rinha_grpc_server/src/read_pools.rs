@@ -0,0 +1,162 @@
+use opentelemetry::{metrics::ObservableGauge, KeyValue};
+use sqlx::PgPool;
+use std::{
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+use uuid::Uuid;
+
+use crate::{
+    pool::{self, NamedPool},
+    utils::env::{PoolConfig, ReadReplicasConfig},
+};
+
+/// Routes the reads to the replicas at `DATABASE_READ_URLS`, round robin
+/// between the healthy ones, falling back to the primary when none is.
+pub struct ReadPools {
+    primary: NamedPool,
+    replicas: Arc<[Replica]>,
+    next: AtomicUsize,
+    /// Pessoas younger than it may not have been replicated yet.
+    lag: Duration,
+    _healthy: Option<ObservableGauge<u64>>,
+}
+
+struct Replica {
+    pool: NamedPool,
+    healthy: AtomicBool,
+}
+
+impl ReadPools {
+    /// The replica pools connect lazily, so an unavailable one does not prevent the startup.
+    pub fn new(
+        primary: PgPool,
+        config: &ReadReplicasConfig,
+        pool_config: &PoolConfig,
+    ) -> Result<Self, sqlx::Error> {
+        let replicas = config
+            .urls
+            .iter()
+            .enumerate()
+            .map(|(index, url)| {
+                let pool = pool::options(pool_config)
+                    .acquire_timeout(config.health_check_interval)
+                    .connect_lazy(url)?;
+                Ok(Replica {
+                    pool: NamedPool {
+                        name: format!("replica_{index}"),
+                        pool,
+                    },
+                    healthy: AtomicBool::new(true),
+                })
+            })
+            .collect::<Result<Arc<[Replica]>, sqlx::Error>>()?;
+        let healthy = (!replicas.is_empty()).then(|| {
+            let replicas = replicas.clone();
+            opentelemetry::global::meter("rinha_grpc_server")
+                .u64_observable_gauge("db.replica.healthy")
+                .with_description("Whether the read replica is receiving reads")
+                .with_callback(move |gauge| {
+                    for (index, replica) in replicas.iter().enumerate() {
+                        gauge.observe(
+                            replica.healthy.load(Ordering::Relaxed) as u64,
+                            &[KeyValue::new("replica", index as i64)],
+                        );
+                    }
+                })
+                .init()
+        });
+        Ok(Self {
+            primary: NamedPool {
+                name: pool::PRIMARY.to_owned(),
+                pool: primary,
+            },
+            replicas,
+            next: AtomicUsize::new(0),
+            lag: config.lag,
+            _healthy: healthy,
+        })
+    }
+
+    pub fn has_replicas(&self) -> bool {
+        !self.replicas.is_empty()
+    }
+
+    /// Checks that every replica takes the connections of the instances, like
+    /// [`pool::connect`] does for the primary. The ones that can't be reached
+    /// are left to the health checks.
+    pub async fn check_capacity(
+        &self,
+        pool_config: &PoolConfig,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        for replica in self.replicas.iter() {
+            let NamedPool { name, pool } = &replica.pool;
+            match pool::capacity(pool).await {
+                Ok(capacity) => pool::check_capacity(name, capacity, pool_config)?,
+                Err(err) => tracing::warn!(
+                    message = "Failed to check the capacity of a read replica.",
+                    name,
+                    %err
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    /// The primary pool and the replica ones, named after their index.
+    pub fn named_pools(&self) -> Vec<NamedPool> {
+        let replicas = self.replicas.iter().map(|replica| replica.pool.clone());
+        std::iter::once(self.primary.clone())
+            .chain(replicas)
+            .collect()
+    }
+
+    pub fn primary(&self) -> &NamedPool {
+        &self.primary
+    }
+
+    /// The pool of the next healthy replica.
+    pub fn pool(&self) -> &NamedPool {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..self.replicas.len())
+            .map(|offset| &self.replicas[(start + offset) % self.replicas.len()])
+            .find(|replica| replica.healthy.load(Ordering::Relaxed))
+            .map_or(&self.primary, |replica| &replica.pool)
+    }
+
+    /// The pool to read the pessoa with this id from, the primary one when it
+    /// was created too recently to be at the replicas for sure.
+    pub fn pool_for_id(&self, id: &Uuid) -> &NamedPool {
+        let recent = id.get_timestamp().is_some_and(|timestamp| {
+            let (secs, nanos) = timestamp.to_unix();
+            let created_at = SystemTime::UNIX_EPOCH + Duration::new(secs, nanos);
+            created_at.elapsed().map_or(true, |age| age < self.lag)
+        });
+        if recent {
+            &self.primary
+        } else {
+            self.pool()
+        }
+    }
+
+    /// Checks every replica at each interval, taking the failing ones out of the rotation.
+    pub fn spawn_health_checks(&self, interval: Duration) {
+        for index in 0..self.replicas.len() {
+            let replicas = self.replicas.clone();
+            tokio::spawn(async move {
+                let replica = &replicas[index];
+                loop {
+                    tokio::time::sleep(interval).await;
+                    let check = sqlx::query("SELECT 1;").execute(&replica.pool.pool);
+                    let healthy = matches!(tokio::time::timeout(interval, check).await, Ok(Ok(_)));
+                    if replica.healthy.swap(healthy, Ordering::Relaxed) != healthy {
+                        tracing::warn!(message = "Read replica health changed.", index, healthy);
+                    }
+                }
+            });
+        }
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    collections::{HashMap, HashSet},
+    sync::RwLock,
+};
+
+use uuid::Uuid;
+
+use crate::models::pessoa::Pessoa;
+
+type Trigram = [char; 3];
+
+/// In memory index answering the searches with the same semantics as
+/// `busca_trgm LIKE '%' || term || '%'`.
+///
+/// Every indexed pessoa has its `busca` split into trigrams, the candidates of
+/// a search are the intersection of the postings of the trigrams found at the
+/// literal parts of the term, which are then matched against the whole pattern.
+/// The pessoas that end up not being inserted are [removed](Self::remove) by
+/// marking their documents, which the searches skip.
+#[derive(Default)]
+pub struct SearchIndex {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    documents: Vec<Document>,
+    postings: HashMap<Trigram, Vec<u32>>,
+    /// Document of each indexed pessoa.
+    positions: HashMap<Uuid, u32>,
+}
+
+struct Document {
+    busca: Box<str>,
+    json: Box<str>,
+    removed: bool,
+}
+
+impl SearchIndex {
+    /// Indexes a pessoa, unless it already is.
+    pub fn insert(&self, pessoa: &Pessoa, json: &str) {
+        let busca = pessoa.busca();
+        let mut inner = self.inner.write().unwrap();
+        if inner.positions.contains_key(&pessoa.id) {
+            return;
+        }
+        let id = inner.documents.len() as u32;
+        inner.positions.insert(pessoa.id, id);
+        let chars: Vec<char> = busca.chars().collect();
+        let trigrams: HashSet<Trigram> = chars.windows(3).map(|w| [w[0], w[1], w[2]]).collect();
+        for trigram in trigrams {
+            inner.postings.entry(trigram).or_default().push(id);
+        }
+        inner.documents.push(Document {
+            busca: busca.into(),
+            json: json.into(),
+            removed: false,
+        });
+    }
+
+    /// Takes out a pessoa that was not inserted, it may be indexed again if it is later.
+    pub fn remove(&self, id: &Uuid) {
+        let mut inner = self.inner.write().unwrap();
+        if let Some(position) = inner.positions.remove(id) {
+            inner.documents[position as usize].removed = true;
+        }
+    }
+
+    /// Returns the amount of found pessoas and their json array, or `None` when
+    /// the term is not a valid `LIKE` pattern.
+    pub fn search(&self, term: &str, limit: usize) -> Option<(usize, String)> {
+        let pattern = Pattern::compile(term)?;
+        let inner = self.inner.read().unwrap();
+        let documents: Box<dyn Iterator<Item = &Document>> = match inner.candidates(&pattern) {
+            Some(candidates) => Box::new(
+                candidates
+                    .into_iter()
+                    .map(|id| &inner.documents[id as usize]),
+            ),
+            None => Box::new(inner.documents.iter()),
+        };
+        let found: Vec<&str> = documents
+            .filter(|document| !document.removed && pattern.matches(&document.busca))
+            .map(|document| &*document.json)
+            .take(limit)
+            .collect();
+        Some((found.len(), format!("[{}]", found.join(","))))
+    }
+}
+
+impl Inner {
+    /// The sorted ids of the documents containing every trigram of the pattern,
+    /// `None` when the pattern has no trigram and all documents are candidates.
+    fn candidates(&self, pattern: &Pattern) -> Option<Vec<u32>> {
+        let mut postings = Vec::new();
+        for trigram in pattern.trigrams() {
+            match self.postings.get(&trigram) {
+                Some(posting) => postings.push(posting),
+                None => return Some(Vec::new()),
+            }
+        }
+        postings.sort_unstable_by_key(|posting| posting.len());
+        let (first, rest) = postings.split_first()?;
+        Some(
+            first
+                .iter()
+                .copied()
+                .filter(|id| rest.iter().all(|posting| posting.binary_search(id).is_ok()))
+                .collect(),
+        )
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Token {
+    /// `%`
+    Any,
+    /// `_`
+    One,
+    Literal(char),
+}
+
+/// A `'%' || term || '%'` pattern with the default `\` escape character.
+struct Pattern {
+    term: String,
+    tokens: Vec<Token>,
+    has_wildcards: bool,
+}
+
+impl Pattern {
+    fn compile(term: &str) -> Option<Self> {
+        let mut tokens = vec![Token::Any];
+        let mut literal = String::with_capacity(term.len());
+        let mut has_wildcards = false;
+        let mut chars = term.chars();
+        while let Some(c) = chars.next() {
+            let token = match c {
+                '%' => Token::Any,
+                '_' => Token::One,
+                // Postgres refuses a pattern ending with the escape character.
+                '\\' => Token::Literal(chars.next()?),
+                c => Token::Literal(c),
+            };
+            match token {
+                Token::Literal(c) => literal.push(c),
+                _ => has_wildcards = true,
+            }
+            tokens.push(token);
+        }
+        tokens.push(Token::Any);
+        Some(Self {
+            term: literal,
+            tokens,
+            has_wildcards,
+        })
+    }
+
+    fn trigrams(&self) -> HashSet<Trigram> {
+        let mut trigrams = HashSet::new();
+        for run in self
+            .tokens
+            .split(|token| !matches!(token, Token::Literal(_)))
+        {
+            let chars: Vec<char> = run
+                .iter()
+                .filter_map(|token| match token {
+                    Token::Literal(c) => Some(*c),
+                    _ => None,
+                })
+                .collect();
+            trigrams.extend(chars.windows(3).map(|w| [w[0], w[1], w[2]]));
+        }
+        trigrams
+    }
+
+    fn matches(&self, text: &str) -> bool {
+        if !self.has_wildcards {
+            return text.contains(self.term.as_str());
+        }
+        let text: Vec<char> = text.chars().collect();
+        let (mut t, mut p) = (0, 0);
+        // Position of the last `%` and of the text when it was reached, to backtrack into.
+        let mut backtrack = None;
+        while t < text.len() {
+            match self.tokens.get(p) {
+                Some(Token::One) => (t, p) = (t + 1, p + 1),
+                Some(Token::Literal(c)) if *c == text[t] => (t, p) = (t + 1, p + 1),
+                Some(Token::Any) => {
+                    backtrack = Some((p, t));
+                    p += 1;
+                }
+                _ => match backtrack {
+                    Some((any, mark)) => {
+                        backtrack = Some((any, mark + 1));
+                        (t, p) = (mark + 1, any + 1);
+                    }
+                    None => return false,
+                },
+            }
+        }
+        self.tokens[p..].iter().all(|token| *token == Token::Any)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn index(people: &[(&str, &str, Option<&[&str]>)]) -> SearchIndex {
+        let index = SearchIndex::default();
+        for (nome, apelido, stack) in people {
+            let pessoa = Pessoa {
+                id: Uuid::now_v7(),
+                apelido: apelido.to_string(),
+                nome: nome.to_string(),
+                nascimento: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+                stack: stack.map(|stack| stack.iter().map(|s| s.to_string()).collect()),
+            };
+            index.insert(&pessoa, &format!("\"{apelido}\""));
+        }
+        index
+    }
+
+    fn found(index: &SearchIndex, term: &str) -> String {
+        index.search(term, 10).unwrap().1
+    }
+
+    #[test]
+    fn finds_the_terms_shorter_than_a_trigram() {
+        let index = index(&[("Ana", "ana", None), ("Bo", "b", Some(&["Go"]))]);
+        assert_eq!(found(&index, "a"), r#"["ana"]"#);
+        assert_eq!(found(&index, "bo"), r#"["b"]"#);
+        assert_eq!(found(&index, "go"), r#"["b"]"#);
+        assert_eq!(found(&index, ""), r#"["ana","b"]"#);
+        assert_eq!(found(&index, "x"), "[]");
+    }
+
+    #[test]
+    fn matches_the_lowercased_busca_like_the_database() {
+        let index = index(&[("José Silva", "Zé", Some(&["Rust", "Node JS"]))]);
+        assert_eq!(found(&index, "josé"), r#"["Zé"]"#);
+        assert_eq!(found(&index, "node js"), r#"["Zé"]"#);
+        // The term is not folded, as `LIKE` is case sensitive.
+        assert_eq!(found(&index, "José"), "[]");
+    }
+
+    #[test]
+    fn matches_multi_byte_characters() {
+        let index = index(&[("João", "ção", None), ("Joao", "cao", None)]);
+        assert_eq!(found(&index, "joão"), r#"["ção"]"#);
+        assert_eq!(found(&index, "ãoç"), r#"["ção"]"#);
+        assert_eq!(found(&index, "jo_o"), r#"["ção","cao"]"#);
+        assert_eq!(found(&index, "ç_o"), r#"["ção"]"#);
+        assert_eq!(found(&index, "j%ç"), r#"["ção"]"#);
+    }
+
+    #[test]
+    fn refuses_a_pattern_ending_with_the_escape_character() {
+        let index = index(&[("Ana", "a_b\\", None)]);
+        assert!(index.search("a\\", 10).is_none());
+        assert_eq!(found(&index, "a\\_b\\\\"), r#"["a_b\"]"#);
+        assert_eq!(found(&index, "n\\_"), "[]");
+    }
+
+    #[test]
+    fn skips_the_removed_pessoas_until_they_are_indexed_again() {
+        let index = index(&[("Ana", "ana", None)]);
+        let pessoa = Pessoa {
+            id: Uuid::now_v7(),
+            apelido: "anabela".to_owned(),
+            nome: "Anabela".to_owned(),
+            nascimento: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            stack: None,
+        };
+        index.insert(&pessoa, r#""anabela""#);
+        index.insert(&pessoa, r#""anabela""#);
+        assert_eq!(found(&index, "ana"), r#"["ana","anabela"]"#);
+        index.remove(&pessoa.id);
+        assert_eq!(found(&index, "ana"), r#"["ana"]"#);
+        assert_eq!(found(&index, "bela"), "[]");
+        index.insert(&pessoa, r#""anabela""#);
+        assert_eq!(found(&index, "bela"), r#"["anabela"]"#);
+    }
+}
@@ -0,0 +1,156 @@
+use std::future::Future;
+
+use dashmap::{mapref::entry::Entry, DashMap};
+use opentelemetry::{metrics::Counter, KeyValue};
+use tokio::sync::watch;
+
+struct SingleflightMetrics {
+    queries: Counter<u64>,
+    coalesced: Counter<u64>,
+    abandoned: Counter<u64>,
+    attributes: [KeyValue; 1],
+}
+
+impl SingleflightMetrics {
+    fn new(name: &'static str) -> Self {
+        let meter = opentelemetry::global::meter("rinha_grpc_server");
+        Self {
+            queries: meter
+                .u64_counter("singleflight.queries")
+                .with_description("Queries run on a cache miss")
+                .init(),
+            coalesced: meter
+                .u64_counter("singleflight.coalesced")
+                .with_description("Cache misses answered by the query of a concurrent one")
+                .init(),
+            abandoned: meter
+                .u64_counter("singleflight.abandoned")
+                .with_description(
+                    "Cache misses whose awaited query was cancelled, so they ran it again",
+                )
+                .init(),
+            attributes: [KeyValue::new("query", name)],
+        }
+    }
+}
+
+/// Runs a single query at a time per key, the concurrent callers with the same
+/// key wait for its result instead of running their own.
+///
+/// The caller running the query, the leader, may be cancelled, as gRPC calls
+/// are dropped once the client goes away. The waiting callers then see the
+/// query abandoned and one of them takes over running it.
+pub struct Singleflight<V> {
+    in_flight: DashMap<String, watch::Receiver<Option<V>>>,
+    metrics: SingleflightMetrics,
+}
+
+/// Ends the flight of the leader, whether it finished or was cancelled.
+struct Flight<'a, V> {
+    in_flight: &'a DashMap<String, watch::Receiver<Option<V>>>,
+    key: &'a str,
+}
+
+impl<V> Drop for Flight<'_, V> {
+    fn drop(&mut self) {
+        // Nobody else adds the key while the flight is on, so the entry is the leader's.
+        self.in_flight.remove(self.key);
+    }
+}
+
+impl<V: Clone> Singleflight<V> {
+    pub fn new(name: &'static str) -> Self {
+        Self {
+            in_flight: DashMap::new(),
+            metrics: SingleflightMetrics::new(name),
+        }
+    }
+
+    pub async fn run<F, Fut>(&self, key: &str, query: F) -> V
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = V>,
+    {
+        loop {
+            let mut receiver = match self.in_flight.entry(key.to_owned()) {
+                Entry::Occupied(entry) => entry.get().clone(),
+                Entry::Vacant(entry) => {
+                    let (sender, receiver) = watch::channel(None);
+                    entry.insert(receiver);
+                    let _flight = Flight {
+                        in_flight: &self.in_flight,
+                        key,
+                    };
+                    self.metrics.queries.add(1, &self.metrics.attributes);
+                    let value = query().await;
+                    sender.send_replace(Some(value.clone()));
+                    return value;
+                }
+            };
+            let value = receiver
+                .wait_for(Option::is_some)
+                .await
+                .map(|value| value.clone());
+            match value {
+                Ok(value) => {
+                    self.metrics.coalesced.add(1, &self.metrics.attributes);
+                    return value.unwrap();
+                }
+                // The leader was cancelled.
+                Err(_) => self.metrics.abandoned.add(1, &self.metrics.attributes),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use tokio::sync::Notify;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn coalesces_the_concurrent_callers() {
+        let flights = Singleflight::new("test");
+        let (queries, landed) = (AtomicUsize::new(0), Notify::new());
+        let query = || async {
+            queries.fetch_add(1, Ordering::AcqRel);
+            landed.notified().await;
+            queries.load(Ordering::Acquire)
+        };
+        // The leader is polled first, so the others find its flight.
+        let values = futures::join!(
+            flights.run("ana", query),
+            flights.run("ana", query),
+            flights.run("ana", query),
+            async { landed.notify_one() },
+        );
+        assert_eq!((values.0, values.1, values.2), (1, 1, 1));
+        assert!(flights.in_flight.is_empty());
+
+        landed.notify_one();
+        assert_eq!(flights.run("ana", query).await, 2);
+    }
+
+    #[tokio::test]
+    async fn retries_the_query_of_a_cancelled_leader() {
+        let flights = Singleflight::new("test");
+        let started = Instant::now();
+        let (cancelled, retried_at) = futures::join!(
+            tokio::time::timeout(
+                Duration::from_millis(10),
+                flights.run("ana", futures::future::pending),
+            ),
+            flights.run("ana", || async { started.elapsed() }),
+        );
+        assert!(cancelled.is_err());
+        // It waited for the leader instead of running its query right away.
+        assert!(retried_at >= Duration::from_millis(10));
+        assert!(flights.in_flight.is_empty());
+    }
+}
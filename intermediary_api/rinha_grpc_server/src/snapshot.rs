@@ -0,0 +1,117 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::File,
+    io::{AsyncBufReadExt, BufReader},
+};
+
+use crate::with_cache::MyRinha;
+
+/// Bumped whenever the entries or the json of the cached pessoas change, so the
+/// snapshots written by older versions are refused instead of misread.
+const SNAPSHOT_VERSION: u32 = 1;
+
+/// First line of a snapshot, followed by one [`Entry`] per line.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    version: u32,
+    written_at: DateTime<Utc>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum Entry {
+    /// An entry of the pessoa by id cache.
+    Pessoa {
+        id: String,
+        json: String,
+    },
+    Apelido {
+        apelido: String,
+    },
+}
+
+/// Writes the pessoa by id cache and the apelido registry to `path`.
+///
+/// The search results are left out, as the pessoas created by other instances
+/// while this one is down would be missing from them. The snapshot is written
+/// next to `path` and then renamed over it, so a crash never leaves half of one.
+/// It blocks, as the caches are locked while being iterated.
+pub fn write(path: &Path, rinha: &MyRinha) -> io::Result<usize> {
+    let partial = path.with_extension("partial");
+    let mut file = io::BufWriter::new(std::fs::File::create(&partial)?);
+    let header = Header {
+        version: SNAPSHOT_VERSION,
+        written_at: Utc::now(),
+    };
+    serde_json::to_writer(&mut file, &header)?;
+    file.write_all(b"\n")?;
+    let pessoas = rinha
+        .pessoa_by_id_map
+        .entries()
+        .map(|(id, json)| Entry::Pessoa {
+            id: id.as_ref().clone(),
+            json,
+        });
+    let apelidos = rinha
+        .pessoa_by_apelido_exists_set
+        .iter()
+        .map(|apelido| Entry::Apelido {
+            apelido: apelido.clone(),
+        });
+    let mut amount = 0;
+    for entry in pessoas.chain(apelidos) {
+        serde_json::to_writer(&mut file, &entry)?;
+        file.write_all(b"\n")?;
+        amount += 1;
+    }
+    file.into_inner()?.sync_all()?;
+    std::fs::rename(&partial, path)?;
+    Ok(amount)
+}
+
+/// Loads the snapshot at `path` into the caches, returning when it was written.
+///
+/// `None` is returned when there is no snapshot or it was written by an
+/// incompatible version, in which case nothing is loaded.
+pub async fn load(path: &Path, rinha: &MyRinha) -> io::Result<Option<DateTime<Utc>>> {
+    let file = match File::open(path).await {
+        Ok(file) => file,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut lines = BufReader::new(file).lines();
+    let header = match lines.next_line().await? {
+        Some(line) => serde_json::from_str::<Header>(&line).ok(),
+        None => None,
+    };
+    let Some(header) = header.filter(|header| header.version == SNAPSHOT_VERSION) else {
+        tracing::warn!(
+            message = "Refused an incompatible cache snapshot.",
+            path = %path.display(),
+            expected_version = SNAPSHOT_VERSION
+        );
+        return Ok(None);
+    };
+    let mut amount = 0;
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str(&line)? {
+            Entry::Pessoa { id, json } => rinha.pessoa_by_id_map.insert(id, json),
+            Entry::Apelido { apelido } => {
+                rinha.pessoa_by_apelido_exists_set.insert(apelido);
+            }
+        }
+        amount += 1;
+    }
+    tracing::info!(
+        message = "Loaded the cache snapshot.",
+        amount,
+        written_at = %header.written_at
+    );
+    Ok(Some(header.written_at))
+}
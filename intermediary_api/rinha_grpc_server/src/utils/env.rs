@@ -1,5 +1,5 @@
 use dotenv::dotenv;
-use std::{env, str::FromStr};
+use std::{env, path::PathBuf, str::FromStr, time::Duration};
 
 pub struct EnvironmentValues {
     pub redis_url: String,
@@ -7,9 +7,391 @@ pub struct EnvironmentValues {
     pub server_port: u16,
     pub rust_env: String,
     pub logger: Option<LoggerOutput>,
-    pub db_pool_max_size: u32,
+    pub database_pool: PoolConfig,
+    pub database_read_replicas: ReadReplicasConfig,
+    pub database_queries: QueryConfig,
+    pub database_migrate_on_startup: bool,
     pub batch_max_insert_size: usize,
-    pub batch_max_wait_on_insert_channel: u64,
+    /// Time a batch is given to fill up since its first pessoa was queued.
+    pub batch_max_wait_on_insert_channel: Duration,
+    /// Adjusts the batch size and wait up to the maximum ones above when set.
+    pub batch_adaptive: Option<AdaptiveBatchConfig>,
+    pub batch_queue_capacity: usize,
+    pub batch_queue_retry_after_secs: u64,
+    pub batch_max_concurrent_inserts: usize,
+    pub batch_insert_method: BatchInsertMethod,
+    /// How long a read given a consistency token waits for its pessoa to be committed.
+    pub consistency_token_timeout: Duration,
+    pub pessoa_by_id_cache: CacheConfig,
+    pub pessoa_search_cache: CacheConfig,
+    /// Ids known not to be of any pessoa, so probing unknown ids does not reach the database.
+    pub pessoa_not_found_cache: Option<CacheConfig>,
+    /// Opt-in, as it holds every pessoa regardless of the cache memory budgets.
+    pub pessoa_search_index: bool,
+    pub pessoa_search_similarity_threshold: f32,
+    pub cache_warmup: CacheWarmupConfig,
+    pub wal: Option<WalConfig>,
+    pub cache_coherency: Option<CacheCoherencyConfig>,
+    pub outbox: Option<OutboxConfig>,
+    pub webhooks: Option<WebhooksConfig>,
+    pub batch_retry: BatchRetryConfig,
+    pub dead_letter_file: PathBuf,
+    /// Time given to the queued pessoas to be inserted once the server is asked to stop.
+    pub shutdown_timeout: Duration,
+    /// Interval between the reconciliations of the pessoa counter with the database.
+    pub count_reconcile_interval: Duration,
+}
+
+/// Sizing and expiration policy of a cache.
+#[derive(Clone, Debug)]
+pub struct CacheConfig {
+    /// Memory budget in bytes, an entry weighs the length of its key plus its value.
+    pub max_bytes: u64,
+    /// When set the cache is bounded by the amount of entries instead of `max_bytes`.
+    pub max_entries: Option<u64>,
+    /// Time to live of an entry since its insertion.
+    pub ttl: Option<Duration>,
+}
+
+impl CacheConfig {
+    /// Reads `{prefix}_MAX_BYTES`, `{prefix}_MAX_ENTRIES` and `{prefix}_TTL_SECS`.
+    fn init(prefix: &str, default_max_bytes: u64) -> Self {
+        Self {
+            max_bytes: parse_var(&format!("{prefix}_MAX_BYTES")).unwrap_or(default_max_bytes),
+            max_entries: parse_var(&format!("{prefix}_MAX_ENTRIES")),
+            ttl: parse_var(&format!("{prefix}_TTL_SECS")).map(Duration::from_secs),
+        }
+    }
+}
+
+/// Filling of the caches at startup.
+#[derive(Clone, Debug)]
+pub struct CacheWarmupConfig {
+    /// Whether the most recent pessoas are loaded from the database into the id
+    /// cache and the apelido registry.
+    pub enabled: bool,
+    /// Memory budget of the loaded pessoas, an id plus its json and apelido.
+    pub max_bytes: u64,
+    /// Snapshot of the caches written at shutdown and loaded at startup.
+    pub snapshot_file: Option<PathBuf>,
+}
+
+impl CacheWarmupConfig {
+    fn init() -> Self {
+        Self {
+            enabled: parse_var("CACHE_WARMUP").unwrap_or(true),
+            max_bytes: parse_var("CACHE_WARMUP_MAX_BYTES").unwrap_or(64 * 1024 * 1024),
+            snapshot_file: parse_var("CACHE_SNAPSHOT_FILE"),
+        }
+    }
+}
+
+/// Connections of each instance to the database.
+#[derive(Clone, Debug)]
+pub struct PoolConfig {
+    pub max_size: u32,
+    /// Connections kept open even when idle.
+    pub min_size: u32,
+    /// Idle connections above `min_size` are closed after it.
+    pub idle_timeout: Option<Duration>,
+    /// Time a query waits for a connection before failing.
+    pub acquire_timeout: Duration,
+    /// Instances sharing the database, each one with a pool of up to `max_size`.
+    pub instances: u32,
+}
+
+impl PoolConfig {
+    fn init() -> Self {
+        Self {
+            max_size: parse_var("DATABASE_POOL_MAX_SIZE").unwrap_or(64),
+            min_size: parse_var("DATABASE_POOL_MIN_SIZE").unwrap_or(0),
+            idle_timeout: Some(parse_var("DATABASE_POOL_IDLE_TIMEOUT_SECS").unwrap_or(600))
+                .filter(|secs| *secs > 0)
+                .map(Duration::from_secs),
+            acquire_timeout: Duration::from_millis(
+                parse_var("DATABASE_POOL_ACQUIRE_TIMEOUT_MS").unwrap_or(30000),
+            ),
+            instances: parse_var("DATABASE_POOL_INSTANCES").unwrap_or(1),
+        }
+    }
+}
+
+/// Read replicas at the comma separated `DATABASE_READ_URLS`.
+#[derive(Clone, Debug)]
+pub struct ReadReplicasConfig {
+    pub urls: Vec<String>,
+    /// Pessoas created more recently than it are read from the primary.
+    pub lag: Duration,
+    pub health_check_interval: Duration,
+}
+
+impl ReadReplicasConfig {
+    fn init() -> Self {
+        Self {
+            urls: env::var("DATABASE_READ_URLS")
+                .map(|urls| {
+                    urls.split(',')
+                        .map(str::trim)
+                        .filter(|url| !url.is_empty())
+                        .map(String::from)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            lag: Duration::from_millis(parse_var("DATABASE_READ_LAG_MS").unwrap_or(1000)),
+            health_check_interval: Duration::from_millis(
+                parse_var("DATABASE_READ_HEALTH_CHECK_INTERVAL_MS").unwrap_or(1000),
+            ),
+        }
+    }
+}
+
+/// Timeouts of the reads answered at the database and logging of the slow ones.
+#[derive(Clone, Debug)]
+pub struct QueryConfig {
+    pub pessoa_by_id_timeout: Option<Duration>,
+    pub pessoa_search_timeout: Option<Duration>,
+    pub count_pessoa_timeout: Option<Duration>,
+    /// Duration above which a query is logged.
+    pub slow_threshold: Option<Duration>,
+    /// Captures the plans of the slow searches when set.
+    pub explain: Option<ExplainConfig>,
+}
+
+impl QueryConfig {
+    fn init() -> Self {
+        let timeout = |name| {
+            parse_var(&format!("DATABASE_QUERY_TIMEOUT_{name}_MS"))
+                .or_else(|| parse_var("DATABASE_QUERY_TIMEOUT_MS"))
+                .map(Duration::from_millis)
+        };
+        Self {
+            pessoa_by_id_timeout: timeout("PESSOA_BY_ID"),
+            pessoa_search_timeout: timeout("PESSOA_SEARCH"),
+            count_pessoa_timeout: timeout("COUNT_PESSOA"),
+            slow_threshold: Some(parse_var("SLOW_QUERY_THRESHOLD_MS").unwrap_or(100))
+                .filter(|threshold| *threshold > 0)
+                .map(Duration::from_millis),
+            explain: parse_var("SLOW_QUERY_EXPLAIN_FILE").map(|file| ExplainConfig {
+                file,
+                sample_rate: parse_var("SLOW_QUERY_EXPLAIN_SAMPLE_RATE").unwrap_or(0.1),
+            }),
+        }
+    }
+}
+
+/// Where the `EXPLAIN (ANALYZE)` of the sampled slow searches is appended to.
+#[derive(Clone, Debug)]
+pub struct ExplainConfig {
+    pub file: PathBuf,
+    /// Fraction of the slow searches explained, between 0 and 1.
+    pub sample_rate: f64,
+}
+
+/// Write ahead log of the pessoas waiting to be inserted, enabled by `WAL_DIR`.
+#[derive(Clone, Debug)]
+pub struct WalConfig {
+    pub dir: PathBuf,
+    /// Size after which a new segment is started.
+    pub segment_max_bytes: u64,
+    /// Whether an append waits for the data to reach the disk.
+    pub fsync: bool,
+}
+
+impl WalConfig {
+    fn init() -> Option<Self> {
+        Some(Self {
+            dir: parse_var("WAL_DIR")?,
+            segment_max_bytes: parse_var("WAL_SEGMENT_MAX_BYTES").unwrap_or(16 * 1024 * 1024),
+            fsync: parse_var("WAL_FSYNC").unwrap_or(true),
+        })
+    }
+}
+
+/// Listening to the pessoas committed by the other instances, enabled by `CACHE_COHERENCY`.
+#[derive(Clone, Debug)]
+pub struct CacheCoherencyConfig {
+    /// How far before losing the listener connection the pessoas are recovered from.
+    pub recovery_window: Duration,
+    pub reconnect_delay: Duration,
+}
+
+impl CacheCoherencyConfig {
+    fn init() -> Option<Self> {
+        parse_var("CACHE_COHERENCY").unwrap_or(false).then(|| Self {
+            recovery_window: Duration::from_secs(
+                parse_var("CACHE_COHERENCY_RECOVERY_WINDOW_SECS").unwrap_or(30),
+            ),
+            reconnect_delay: Duration::from_millis(
+                parse_var("CACHE_COHERENCY_RECONNECT_DELAY_MS").unwrap_or(1000),
+            ),
+        })
+    }
+}
+
+/// Events of the pessoas relayed to `OUTBOX_SINK`, which enables them.
+#[derive(Clone, Debug)]
+pub struct OutboxConfig {
+    pub sink: EventSinkConfig,
+    /// Maximum amount of events given to the sink at once.
+    pub batch_size: i64,
+    /// Interval between the reads of the outbox once every event was delivered.
+    pub poll_interval: Duration,
+    /// Delay before retrying a failed delivery, doubled at each one of the following.
+    pub retry_base_delay: Duration,
+    pub retry_max_delay: Duration,
+}
+
+impl OutboxConfig {
+    fn init() -> Option<Self> {
+        let sink = match env::var("OUTBOX_SINK").ok()?.to_lowercase().as_str() {
+            "stdout" => EventSinkConfig::Stdout,
+            "file" => EventSinkConfig::File(
+                parse_var("OUTBOX_FILE").unwrap_or_else(|| "events.ndjson".into()),
+            ),
+            "webhook" => EventSinkConfig::Webhook {
+                url: env::var("OUTBOX_WEBHOOK_URL")
+                    .expect("OUTBOX_WEBHOOK_URL must be set for the webhook sink"),
+                timeout: Duration::from_millis(
+                    parse_var("OUTBOX_WEBHOOK_TIMEOUT_MS").unwrap_or(5000),
+                ),
+            },
+            sink => panic!("OUTBOX_SINK must be 'stdout', 'file' or 'webhook', not '{sink}'"),
+        };
+        Some(Self {
+            sink,
+            batch_size: parse_var("OUTBOX_BATCH_SIZE").unwrap_or(256),
+            poll_interval: Duration::from_millis(
+                parse_var("OUTBOX_POLL_INTERVAL_MS").unwrap_or(500),
+            ),
+            retry_base_delay: Duration::from_millis(
+                parse_var("OUTBOX_RETRY_BASE_DELAY_MS").unwrap_or(100),
+            ),
+            retry_max_delay: Duration::from_millis(
+                parse_var("OUTBOX_RETRY_MAX_DELAY_MS").unwrap_or(30000),
+            ),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub enum EventSinkConfig {
+    /// NDJSON lines written to the standard output.
+    Stdout,
+    /// NDJSON lines appended to a file.
+    File(PathBuf),
+    /// JSON arrays of events posted to an URL.
+    Webhook { url: String, timeout: Duration },
+}
+
+/// Deliveries of the events to the webhook subscriptions, enabled by `WEBHOOKS`.
+#[derive(Clone, Debug)]
+pub struct WebhooksConfig {
+    /// Failed deliveries in a row after which a subscription is disabled.
+    pub max_failures: u32,
+    /// Delay before retrying a failed delivery, doubled at each one of the following.
+    pub retry_base_delay: Duration,
+    pub retry_max_delay: Duration,
+    /// Interval between the checks for subscriptions having events to be delivered.
+    pub poll_interval: Duration,
+    pub timeout: Duration,
+    /// Maximum amount of events posted at once.
+    pub batch_size: i64,
+    /// Whether the subscriptions may target private, loopback or link local addresses.
+    pub allow_private_targets: bool,
+}
+
+impl WebhooksConfig {
+    fn init() -> Option<Self> {
+        parse_var("WEBHOOKS").unwrap_or(false).then(|| Self {
+            max_failures: parse_var("WEBHOOK_MAX_FAILURES").unwrap_or(10),
+            retry_base_delay: Duration::from_millis(
+                parse_var("WEBHOOK_RETRY_BASE_DELAY_MS").unwrap_or(1000),
+            ),
+            retry_max_delay: Duration::from_millis(
+                parse_var("WEBHOOK_RETRY_MAX_DELAY_MS").unwrap_or(300_000),
+            ),
+            poll_interval: Duration::from_millis(
+                parse_var("WEBHOOK_POLL_INTERVAL_MS").unwrap_or(1000),
+            ),
+            timeout: Duration::from_millis(parse_var("WEBHOOK_TIMEOUT_MS").unwrap_or(5000)),
+            batch_size: parse_var("WEBHOOK_BATCH_SIZE").unwrap_or(100),
+            allow_private_targets: parse_var("WEBHOOK_ALLOW_PRIVATE_TARGETS").unwrap_or(false),
+        })
+    }
+}
+
+/// Lower bounds and goal of the adaptive batch size and wait.
+#[derive(Clone, Debug)]
+pub struct AdaptiveBatchConfig {
+    pub min_size: usize,
+    pub min_wait: Duration,
+    /// Commit latency above which the batches are made smaller.
+    pub target_commit_latency: Duration,
+}
+
+impl AdaptiveBatchConfig {
+    fn init(max_size: usize, max_wait: Duration) -> Option<Self> {
+        parse_var("BATCH_ADAPTIVE").unwrap_or(false).then(|| Self {
+            min_size: parse_var("BATCH_MIN_INSERT_SIZE")
+                .unwrap_or(16)
+                .clamp(1, max_size.max(1)),
+            min_wait: Duration::from_millis(
+                parse_var("BATCH_MIN_WAIT_ON_INSERT_CHANNEL_MS").unwrap_or(1),
+            )
+            .min(max_wait),
+            target_commit_latency: Duration::from_millis(
+                parse_var("BATCH_TARGET_COMMIT_LATENCY_MS").unwrap_or(50),
+            ),
+        })
+    }
+}
+
+/// Retries of a batch insert failing with a transient error.
+#[derive(Clone, Debug)]
+pub struct BatchRetryConfig {
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled at each one of the following.
+    pub base_delay: Duration,
+    pub max_delay: Duration,
+}
+
+impl BatchRetryConfig {
+    fn init() -> Self {
+        Self {
+            max_retries: parse_var("BATCH_INSERT_MAX_RETRIES").unwrap_or(5),
+            base_delay: Duration::from_millis(
+                parse_var("BATCH_INSERT_RETRY_BASE_DELAY_MS").unwrap_or(100),
+            ),
+            max_delay: Duration::from_millis(
+                parse_var("BATCH_INSERT_RETRY_MAX_DELAY_MS").unwrap_or(5000),
+            ),
+        }
+    }
+}
+
+/// How the batches of pessoas are written to the database.
+#[derive(Clone, Copy, Debug)]
+pub enum BatchInsertMethod {
+    /// A multi row `INSERT ... VALUES`, limited to about 13k rows by the 65535 bind parameters.
+    Values,
+    /// A binary `COPY` into a staging table followed by an `INSERT ... SELECT`.
+    Copy,
+}
+
+impl FromStr for BatchInsertMethod {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "values" => Ok(Self::Values),
+            "copy" => Ok(Self::Copy),
+            _ => Err(()),
+        }
+    }
+}
+
+fn parse_var<T: FromStr>(key: &str) -> Option<T> {
+    env::var(key).ok().and_then(|s| s.parse().ok())
 }
 
 pub enum LoggerOutput {
@@ -30,8 +412,22 @@ impl FromStr for LoggerOutput {
 }
 
 impl EnvironmentValues {
+    /// Whether the batch inserts write the events of the pessoas to the outbox.
+    pub fn writes_events(&self) -> bool {
+        self.outbox.is_some() || self.webhooks.is_some()
+    }
+
     pub fn init() -> Self {
         dotenv().ok();
+        let database_pool = PoolConfig::init();
+        let db_pool_max_size = database_pool.max_size;
+        let batch_max_insert_size = parse_var("BATCH_MAX_INSERT_SIZE").unwrap_or(256);
+        // Whole seconds are still read from the older variable.
+        let batch_max_wait_on_insert_channel = parse_var("BATCH_MAX_WAIT_ON_INSERT_CHANNEL_MS")
+            .map(Duration::from_millis)
+            .unwrap_or_else(|| {
+                Duration::from_secs(parse_var("BATCH_MAX_WAIT_ON_INSERT_CHANNEL").unwrap_or(1))
+            });
         Self {
             database_url: env::var("DATABASE_URL").expect("DATABASE_URL must be set"),
             redis_url: env::var("REDIS_URL").expect("REDIS_URL must be set"),
@@ -40,25 +436,55 @@ impl EnvironmentValues {
                 .parse()
                 .expect("SERVER_PORT must be a number"),
             rust_env: env::var("RUST_ENV").unwrap_or_else(|_| "dev".into()),
-            logger: std::env::var("LOGGER_OUTPUT")
-                .ok()
-                .map(|s| s.parse().ok())
-                .flatten(),
-            db_pool_max_size: std::env::var("DATABASE_POOL_MAX_SIZE")
-                .map(|s| s.parse().ok())
-                .ok()
-                .flatten()
-                .unwrap_or(256),
-            batch_max_insert_size: std::env::var("BATCH_MAX_INSERT_SIZE")
-                .map(|s| s.parse().ok())
-                .ok()
-                .flatten()
-                .unwrap_or(256),
-            batch_max_wait_on_insert_channel: std::env::var("BATCH_MAX_WAIT_ON_INSERT_CHANNEL")
-                .map(|s| s.parse().ok())
-                .ok()
-                .flatten()
-                .unwrap_or(1),
+            logger: parse_var("LOGGER_OUTPUT"),
+            database_pool,
+            database_read_replicas: ReadReplicasConfig::init(),
+            database_queries: QueryConfig::init(),
+            database_migrate_on_startup: parse_var("DATABASE_MIGRATE_ON_STARTUP").unwrap_or(true),
+            batch_max_insert_size,
+            batch_max_wait_on_insert_channel,
+            batch_adaptive: AdaptiveBatchConfig::init(
+                batch_max_insert_size,
+                batch_max_wait_on_insert_channel,
+            ),
+            batch_queue_capacity: parse_var("BATCH_QUEUE_CAPACITY").unwrap_or(65536),
+            batch_queue_retry_after_secs: parse_var("BATCH_QUEUE_RETRY_AFTER_SECS").unwrap_or(1),
+            // Each insert holds a connection, leaving none to the reads would make them time out.
+            batch_max_concurrent_inserts: parse_var("BATCH_MAX_CONCURRENT_INSERTS")
+                .unwrap_or(db_pool_max_size as usize / 2)
+                .clamp(1, (db_pool_max_size as usize).max(1)),
+            batch_insert_method: parse_var("BATCH_INSERT_METHOD")
+                .unwrap_or(BatchInsertMethod::Values),
+            consistency_token_timeout: Duration::from_millis(
+                parse_var("CONSISTENCY_TOKEN_TIMEOUT_MS").unwrap_or(1000),
+            ),
+            pessoa_by_id_cache: CacheConfig::init("PESSOA_BY_ID_CACHE", 128 * 1024 * 1024),
+            pessoa_search_cache: CacheConfig::init("PESSOA_SEARCH_CACHE", 64 * 1024 * 1024),
+            pessoa_not_found_cache: parse_var("PESSOA_NOT_FOUND_CACHE").unwrap_or(true).then(
+                || {
+                    let config = CacheConfig::init("PESSOA_NOT_FOUND_CACHE", 8 * 1024 * 1024);
+                    CacheConfig {
+                        // Short lived, as the pessoas created by other instances are unknown at first.
+                        ttl: Some(config.ttl.unwrap_or(Duration::from_secs(5))),
+                        ..config
+                    }
+                },
+            ),
+            pessoa_search_index: parse_var("PESSOA_SEARCH_INDEX").unwrap_or(false),
+            pessoa_search_similarity_threshold: parse_var("PESSOA_SEARCH_SIMILARITY_THRESHOLD")
+                .unwrap_or(0.6),
+            cache_warmup: CacheWarmupConfig::init(),
+            wal: WalConfig::init(),
+            cache_coherency: CacheCoherencyConfig::init(),
+            outbox: OutboxConfig::init(),
+            webhooks: WebhooksConfig::init(),
+            batch_retry: BatchRetryConfig::init(),
+            dead_letter_file: parse_var("DEAD_LETTER_FILE")
+                .unwrap_or_else(|| "dead_letters.ndjson".into()),
+            shutdown_timeout: Duration::from_secs(parse_var("SHUTDOWN_TIMEOUT_SECS").unwrap_or(8)),
+            count_reconcile_interval: Duration::from_secs(
+                parse_var("COUNT_RECONCILE_INTERVAL_SECS").unwrap_or(60),
+            ),
         }
     }
 }
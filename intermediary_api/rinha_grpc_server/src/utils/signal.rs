@@ -0,0 +1,12 @@
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Resolves once the process is asked to stop, either by a SIGTERM
+/// (e.g. `docker-compose down`) or by a SIGINT (Ctrl+C).
+pub async fn shutdown() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("Failed to listen for SIGTERM");
+    tokio::select! {
+        _ = sigterm.recv() => (),
+        _ = tokio::signal::ctrl_c() => (),
+    }
+    tracing::info!("Shutting down.");
+}
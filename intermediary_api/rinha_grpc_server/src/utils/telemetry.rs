@@ -1,5 +1,9 @@
+use opentelemetry::sdk::metrics::MeterProvider;
+use std::sync::OnceLock;
 use tracing_subscriber::{fmt, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter};
 
+static METER_PROVIDER: OnceLock<MeterProvider> = OnceLock::new();
+
 pub fn init() {
     tracing_subscriber::registry()
         .with(
@@ -12,4 +16,18 @@ pub fn init() {
 pub fn init_otel() {
     init_tracing_opentelemetry::tracing_subscriber_ext::init_subscribers()
         .expect("init subscribers");
+    // The exporter reads the same `OTEL_EXPORTER_OTLP_*` variables as the traces one.
+    let meter_provider = opentelemetry_otlp::new_pipeline()
+        .metrics(opentelemetry::runtime::TokioCurrentThread)
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+        .build()
+        .expect("init meter provider");
+    let _ = METER_PROVIDER.set(meter_provider);
+}
+
+pub fn shutdown_otel() {
+    opentelemetry::global::shutdown_tracer_provider();
+    if let Some(meter_provider) = METER_PROVIDER.get() {
+        let _ = meter_provider.shutdown();
+    }
 }
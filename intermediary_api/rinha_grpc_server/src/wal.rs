@@ -0,0 +1,295 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{mpsc, oneshot};
+
+use crate::{models::pessoa::Pessoa, utils::env::WalConfig};
+
+/// Id of the segment file a pessoa was appended to.
+pub type SegmentId = u64;
+
+/// Append only log of the pessoas accepted but not yet committed to the database.
+///
+/// Records are framed as `[len: u32][crc32: u32][json]` and appended by a
+/// dedicated thread that syncs every record that arrived while the previous
+/// sync was running at once (group commit). Segments are rotated when they
+/// exceed `segment_max_bytes` and removed once every pessoa in them has been
+/// [released](Wal::release).
+pub struct Wal {
+    appender: mpsc::UnboundedSender<Append>,
+    segments: Arc<Mutex<Segments>>,
+}
+
+struct Append {
+    record: Vec<u8>,
+    ack: oneshot::Sender<io::Result<SegmentId>>,
+}
+
+struct Segments {
+    dir: PathBuf,
+    current: SegmentId,
+    /// Amount of unreleased pessoas of each segment.
+    pending: BTreeMap<SegmentId, usize>,
+}
+
+impl Segments {
+    fn remove_if_released(&mut self, segment: SegmentId) {
+        if segment != self.current && self.pending.get(&segment) == Some(&0) {
+            self.pending.remove(&segment);
+            if let Err(err) = fs::remove_file(segment_path(&self.dir, segment)) {
+                tracing::error!(message = "Failed to remove a write ahead log segment.", segment, %err);
+            }
+        }
+    }
+}
+
+impl Wal {
+    /// Reads the pessoas of the segments left by a previous execution, they
+    /// must be stored before [`Wal::open`] removes those segments.
+    pub fn replay(dir: &Path) -> io::Result<Vec<Pessoa>> {
+        let mut pessoas = Vec::new();
+        for segment in segment_ids(dir)? {
+            let mut reader = BufReader::new(File::open(segment_path(dir, segment))?);
+            while let Some(record) = read_record(&mut reader)? {
+                match serde_json::from_slice(&record) {
+                    Ok(pessoa) => pessoas.push(pessoa),
+                    Err(err) => tracing::error!(
+                        message = "Skipping an invalid write ahead log record.",
+                        segment,
+                        %err
+                    ),
+                }
+            }
+        }
+        Ok(pessoas)
+    }
+
+    pub fn open(config: &WalConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.dir)?;
+        let existing = segment_ids(&config.dir)?;
+        for segment in existing.iter() {
+            fs::remove_file(segment_path(&config.dir, *segment))?;
+        }
+        let current = existing.last().map_or(0, |segment| segment + 1);
+        let segments = Arc::new(Mutex::new(Segments {
+            dir: config.dir.clone(),
+            current,
+            pending: BTreeMap::from([(current, 0)]),
+        }));
+        let mut writer = SegmentWriter::create(&config.dir, current)?;
+        let (appender, mut appends) = mpsc::unbounded_channel::<Append>();
+        let thread_segments = segments.clone();
+        let (dir, segment_max_bytes, fsync) =
+            (config.dir.clone(), config.segment_max_bytes, config.fsync);
+        std::thread::Builder::new()
+            .name("wal-writer".into())
+            .spawn(move || {
+                while let Some(append) = appends.blocking_recv() {
+                    let mut group = vec![append];
+                    while let Ok(append) = appends.try_recv() {
+                        group.push(append);
+                    }
+                    let written = writer.write(&group, fsync);
+                    let segment = writer.segment;
+                    if written.is_ok() {
+                        thread_segments
+                            .lock()
+                            .unwrap()
+                            .pending
+                            .entry(segment)
+                            .and_modify(|pending| *pending += group.len());
+                    }
+                    for append in group {
+                        let _ = append.ack.send(match written.as_ref() {
+                            Ok(_) => Ok(segment),
+                            Err(err) => Err(io::Error::new(err.kind(), err.to_string())),
+                        });
+                    }
+                    // A failed write could have left a partial record, which ends the segment.
+                    if written.is_err() || writer.len >= segment_max_bytes {
+                        match SegmentWriter::create(&dir, segment + 1) {
+                            Ok(next) => {
+                                writer = next;
+                                let mut segments = thread_segments.lock().unwrap();
+                                segments.current = segment + 1;
+                                segments.pending.insert(segment + 1, 0);
+                                segments.remove_if_released(segment);
+                            }
+                            Err(err) => tracing::error!(
+                                message = "Failed to rotate the write ahead log.",
+                                %err
+                            ),
+                        }
+                    }
+                }
+            })?;
+        Ok(Self { appender, segments })
+    }
+
+    /// Returns once the pessoa is durable, along with the segment to release when it is committed.
+    pub async fn append(&self, pessoa: &Pessoa) -> io::Result<SegmentId> {
+        let record = serde_json::to_vec(pessoa)?;
+        let (ack, acked) = oneshot::channel();
+        self.appender
+            .send(Append { record, ack })
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "wal writer stopped"))?;
+        acked
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "wal writer stopped"))?
+    }
+
+    /// Marks `amount` pessoas of the segment as committed to the database.
+    pub fn release(&self, segment: SegmentId, amount: usize) {
+        let mut segments = self.segments.lock().unwrap();
+        if let Some(pending) = segments.pending.get_mut(&segment) {
+            *pending = pending.saturating_sub(amount);
+        }
+        segments.remove_if_released(segment);
+    }
+}
+
+struct SegmentWriter {
+    segment: SegmentId,
+    file: BufWriter<File>,
+    len: u64,
+}
+
+impl SegmentWriter {
+    fn create(dir: &Path, segment: SegmentId) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(segment_path(dir, segment))?;
+        // Makes the new segment entry itself durable.
+        File::open(dir)?.sync_all()?;
+        Ok(Self {
+            segment,
+            file: BufWriter::new(file),
+            len: 0,
+        })
+    }
+
+    fn write(&mut self, group: &[Append], fsync: bool) -> io::Result<()> {
+        for Append { record, .. } in group {
+            self.file.write_all(&(record.len() as u32).to_le_bytes())?;
+            self.file
+                .write_all(&crc32fast::hash(record).to_le_bytes())?;
+            self.file.write_all(record)?;
+            self.len += 8 + record.len() as u64;
+        }
+        self.file.flush()?;
+        if fsync {
+            self.file.get_ref().sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads the next record, a truncated or corrupted one ends the segment as it
+/// can only be the result of a crash in the middle of a write.
+fn read_record(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut header = [0; 8];
+    if let Err(err) = reader.read_exact(&mut header) {
+        return match err.kind() {
+            io::ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(err),
+        };
+    }
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let mut record = vec![0; len];
+    if let Err(err) = reader.read_exact(&mut record) {
+        return match err.kind() {
+            io::ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(err),
+        };
+    }
+    Ok((crc32fast::hash(&record) == crc).then_some(record))
+}
+
+fn segment_path(dir: &Path, segment: SegmentId) -> PathBuf {
+    dir.join(format!("{segment:020}.wal"))
+}
+
+fn segment_ids(dir: &Path) -> io::Result<Vec<SegmentId>> {
+    let mut segments = Vec::new();
+    match fs::read_dir(dir) {
+        Ok(entries) => {
+            for entry in entries {
+                let name = entry?.file_name();
+                if let Some(segment) = name
+                    .to_str()
+                    .and_then(|name| name.strip_suffix(".wal"))
+                    .and_then(|segment| segment.parse().ok())
+                {
+                    segments.push(segment);
+                }
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => (),
+        Err(err) => return Err(err),
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn pessoa(apelido: &str) -> Pessoa {
+        Pessoa {
+            id: Uuid::now_v7(),
+            apelido: apelido.to_owned(),
+            nome: "José".to_owned(),
+            nascimento: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            stack: Some(vec!["Rust".to_owned()]),
+        }
+    }
+
+    #[tokio::test]
+    async fn replays_the_records_before_a_truncated_write() {
+        let config = WalConfig {
+            dir: std::env::temp_dir().join(format!("rinha-wal-{}", Uuid::new_v4())),
+            segment_max_bytes: 1 << 20,
+            fsync: false,
+        };
+        let wal = Wal::open(&config).unwrap();
+        let segment = wal.append(&pessoa("ana")).await.unwrap();
+        wal.append(&pessoa("zé")).await.unwrap();
+        drop(wal);
+
+        // A crash in the middle of a write leaves the header of a record without all of its bytes.
+        let record = serde_json::to_vec(&pessoa("bia")).unwrap();
+        let mut file = OpenOptions::new()
+            .append(true)
+            .open(segment_path(&config.dir, segment))
+            .unwrap();
+        file.write_all(&(record.len() as u32).to_le_bytes())
+            .unwrap();
+        file.write_all(&crc32fast::hash(&record).to_le_bytes())
+            .unwrap();
+        file.write_all(&record[..record.len() / 2]).unwrap();
+        drop(file);
+
+        let apelidos: Vec<String> = Wal::replay(&config.dir)
+            .unwrap()
+            .into_iter()
+            .map(|pessoa| pessoa.apelido)
+            .collect();
+        assert_eq!(apelidos, ["ana", "zé"]);
+
+        // Reopening starts a new segment, so the truncated record is gone for good.
+        drop(Wal::open(&config).unwrap());
+        assert!(Wal::replay(&config.dir).unwrap().is_empty());
+        fs::remove_dir_all(&config.dir).unwrap();
+    }
+}
@@ -0,0 +1,494 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Instant,
+};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use hyper::client::connect::dns::Name;
+use reqwest::{
+    dns::{Addrs, Resolve, Resolving},
+    redirect, Url,
+};
+use sha2::Sha256;
+use sqlx::PgPool;
+use uuid::Uuid;
+
+use crate::{
+    event_sink::{Event, EventSink, SinkError},
+    outbox::relay,
+    rinha::{WebhookDelivery, WebhookSubscription},
+    utils::env::WebhooksConfig,
+};
+
+/// Lets the subscribers check a delivery was sent by this server, see [`signature`].
+const SIGNATURE_HEADER: &str = "X-Rinha-Signature";
+const SUBSCRIPTION_HEADER: &str = "X-Rinha-Subscription";
+
+/// Name of the outbox cursor of a subscription.
+fn cursor_name(id: &Uuid) -> String {
+    format!("webhook_subscription:{id}")
+}
+
+/// `t={timestamp},v1={hex HMAC-SHA256 of "{timestamp}.{body}"}`, the timestamp
+/// being signed as well lets the subscribers refuse replayed deliveries.
+fn signature(secret: &str, timestamp: i64, body: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes keys of any size");
+    mac.update(format!("{timestamp}.").as_bytes());
+    mac.update(body);
+    format!(
+        "t={timestamp},v1={}",
+        hex::encode(mac.finalize().into_bytes())
+    )
+}
+
+/// Whether an address is reachable from the internet, rather than being
+/// private, loopback, link local or otherwise special purpose.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                || a == 0
+                // Shared address space of the carrier grade NATs.
+                || (a == 100 && b & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local and link local.
+                    || first & 0xfe00 == 0xfc00
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Resolves the hosts of the subscriptions to their public addresses only, so
+/// a subscription can't reach the internal network, not even by changing its
+/// DNS records once created.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_owned();
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((host.as_str(), 0))
+                .await?
+                .filter(|addr| is_public(addr.ip()))
+                .collect();
+            if addrs.is_empty() {
+                return Err(format!("{host} has no public address").into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The client posting the deliveries, which neither follows redirects nor
+/// connects to private addresses unless they are allowed.
+pub fn client(config: &WebhooksConfig) -> reqwest::Result<reqwest::Client> {
+    let builder = reqwest::Client::builder()
+        .timeout(config.timeout)
+        .redirect(redirect::Policy::none());
+    match config.allow_private_targets {
+        true => builder.build(),
+        false => builder.dns_resolver(Arc::new(PublicResolver)).build(),
+    }
+}
+
+/// Refuses the URLs of hosts without a public address. The addresses are
+/// checked again at every delivery, by the resolver of the [`client`].
+pub async fn check_target(url: &Url) -> Result<(), String> {
+    let Some(host) = url.host_str() else {
+        return Err("The url must have a host".to_owned());
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let public = match host.parse::<IpAddr>() {
+        Ok(ip) => is_public(ip),
+        Err(_) => tokio::net::lookup_host((host, 0))
+            .await
+            .map_err(|err| format!("Failed to resolve {host}: {err}"))?
+            .any(|addr| is_public(addr.ip())),
+    };
+    match public {
+        true => Ok(()),
+        false => Err(format!("{host} is not a public address")),
+    }
+}
+
+/// The resolver is skipped for the URLs with an address instead of a host.
+fn has_private_address(url: &str) -> bool {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| {
+            let host = url
+                .host_str()?
+                .trim_start_matches('[')
+                .trim_end_matches(']');
+            host.parse::<IpAddr>().ok()
+        })
+        .is_some_and(|ip| !is_public(ip))
+}
+
+struct Subscription {
+    id: Uuid,
+    url: String,
+    secret: String,
+    consecutive_failures: i32,
+}
+
+/// Posts the events to a subscription, logging every attempt.
+struct SubscriptionSink<'a> {
+    db: &'a PgPool,
+    client: &'a reqwest::Client,
+    subscription: &'a Subscription,
+    allow_private_targets: bool,
+}
+
+#[tonic::async_trait]
+impl EventSink for SubscriptionSink<'_> {
+    fn name(&self) -> String {
+        cursor_name(&self.subscription.id)
+    }
+
+    async fn deliver(&self, events: &[Event]) -> Result<(), SinkError> {
+        let body = serde_json::to_vec(events)?;
+        let signature = signature(&self.subscription.secret, Utc::now().timestamp(), &body);
+        let started = Instant::now();
+        // Subscribed before the private addresses were refused, or while they were allowed.
+        let response = if !self.allow_private_targets && has_private_address(&self.subscription.url)
+        {
+            Err(SinkError::from("The url is not a public address"))
+        } else {
+            self.client
+                .post(&self.subscription.url)
+                .header(reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, signature)
+                .header(SUBSCRIPTION_HEADER, self.subscription.id.to_string())
+                .body(body)
+                .send()
+                .await
+                .map_err(SinkError::from)
+        };
+        let (status_code, delivered) = match response {
+            Ok(response) => (
+                Some(response.status().as_u16() as i32),
+                response
+                    .error_for_status()
+                    .map(|_| ())
+                    .map_err(SinkError::from),
+            ),
+            Err(err) => (None, Err(err)),
+        };
+        let logged = sqlx::query(
+            "INSERT INTO webhook_deliveries (subscription_id, first_seq, last_seq, events, status_code, error, duration_ms) VALUES ($1, $2, $3, $4, $5, $6, $7);",
+        )
+        .bind(self.subscription.id)
+        .bind(events.first().map(|event| event.seq))
+        .bind(events.last().map(|event| event.seq))
+        .bind(events.len() as i32)
+        .bind(status_code)
+        .bind(delivered.as_ref().err().map(ToString::to_string))
+        .bind(started.elapsed().as_millis() as i64)
+        .execute(self.db)
+        .await;
+        if let Err(err) = logged {
+            tracing::warn!(message = "Failed to log a webhook delivery.", %err);
+        }
+        Ok(delivered?)
+    }
+}
+
+/// Delivers the outbox events to every enabled webhook subscription.
+///
+/// Each subscription is relayed from its own cursor, so a failing one does not
+/// hold back the others. Its failures are kept at the database, along with when
+/// to retry it, and it is disabled once too many of them happen in a row.
+pub async fn webhooks_task(db: PgPool, client: reqwest::Client, config: WebhooksConfig) {
+    loop {
+        match due_subscriptions(&db).await {
+            Ok(subscriptions) => {
+                let dispatches = subscriptions
+                    .iter()
+                    .map(|subscription| dispatch(&db, &client, subscription, &config));
+                futures::future::join_all(dispatches).await;
+            }
+            Err(err) => tracing::warn!(message = "Failed to read the webhook subscriptions.", %err),
+        }
+        tokio::time::sleep(config.poll_interval).await;
+    }
+}
+
+async fn due_subscriptions(db: &PgPool) -> Result<Vec<Subscription>, sqlx::Error> {
+    let subscriptions = sqlx::query_as::<_, (Uuid, String, String, i32)>(
+        "SELECT id, url, secret, consecutive_failures FROM webhook_subscriptions WHERE enabled AND next_attempt_at <= NOW();",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(subscriptions
+        .into_iter()
+        .map(|(id, url, secret, consecutive_failures)| Subscription {
+            id,
+            url,
+            secret,
+            consecutive_failures,
+        })
+        .collect())
+}
+
+/// Delivers the pending events of the subscription, until one delivery fails.
+async fn dispatch(
+    db: &PgPool,
+    client: &reqwest::Client,
+    subscription: &Subscription,
+    config: &WebhooksConfig,
+) {
+    let sink = SubscriptionSink {
+        db,
+        client,
+        subscription,
+        allow_private_targets: config.allow_private_targets,
+    };
+    let name = sink.name();
+    let mut failing = subscription.consecutive_failures > 0;
+    loop {
+        match relay(db, &sink, &name, config.batch_size).await {
+            Ok(delivered) => {
+                // Nothing was delivered when another instance holds the cursor.
+                if failing && delivered > 0 {
+                    failing = false;
+                    if let Err(err) = sqlx::query(
+                        "UPDATE webhook_subscriptions SET consecutive_failures = 0 WHERE id = $1;",
+                    )
+                    .bind(subscription.id)
+                    .execute(db)
+                    .await
+                    {
+                        tracing::warn!(message = "Failed to reset a webhook subscription.", %err);
+                    }
+                }
+                if delivered < config.batch_size as u64 {
+                    return;
+                }
+            }
+            Err(err) => {
+                tracing::warn!(
+                    message = "Failed a webhook delivery.",
+                    subscription = %subscription.id,
+                    %err
+                );
+                if let Err(err) = record_failure(db, subscription, config).await {
+                    tracing::warn!(message = "Failed to record a webhook failure.", %err);
+                }
+                return;
+            }
+        }
+    }
+}
+
+/// Backs off the subscription, or disables it after too many failures in a row.
+async fn record_failure(
+    db: &PgPool,
+    subscription: &Subscription,
+    config: &WebhooksConfig,
+) -> Result<(), sqlx::Error> {
+    let delay =
+        config.retry_base_delay * 2u32.pow(subscription.consecutive_failures.min(16) as u32);
+    let (enabled,) = sqlx::query_as::<_, (bool,)>(
+        "UPDATE webhook_subscriptions SET consecutive_failures = consecutive_failures + 1, enabled = consecutive_failures + 1 < $2, next_attempt_at = NOW() + $3 * INTERVAL '1 millisecond' WHERE id = $1 RETURNING enabled;",
+    )
+    .bind(subscription.id)
+    .bind(config.max_failures as i32)
+    .bind(delay.min(config.retry_max_delay).as_millis() as i64)
+    .fetch_one(db)
+    .await?;
+    if !enabled {
+        tracing::error!(
+            message = "Disabled a webhook subscription after repeated failures.",
+            subscription = %subscription.id,
+            url = subscription.url
+        );
+    }
+    Ok(())
+}
+
+type SubscriptionRow = (Uuid, String, bool, i32, DateTime<Utc>);
+
+fn to_reply(
+    (id, url, enabled, consecutive_failures, created_at): SubscriptionRow,
+) -> WebhookSubscription {
+    WebhookSubscription {
+        id: id.to_string(),
+        url,
+        secret: None,
+        enabled,
+        consecutive_failures: consecutive_failures as u32,
+        created_at: created_at.to_rfc3339(),
+    }
+}
+
+/// Subscribes the URL to the events committed from now on, a secret is generated when not given.
+pub(crate) async fn create_subscription(
+    db: &PgPool,
+    url: String,
+    secret: Option<String>,
+) -> Result<WebhookSubscription, sqlx::Error> {
+    let id = Uuid::now_v7();
+    let secret = secret.unwrap_or_else(|| hex::encode(rand::random::<[u8; 32]>()));
+    let mut tx = db.begin().await?;
+    let row = sqlx::query_as::<_, SubscriptionRow>(
+        "INSERT INTO webhook_subscriptions (id, url, secret) VALUES ($1, $2, $3) RETURNING id, url, enabled, consecutive_failures, created_at;",
+    )
+    .bind(id)
+    .bind(url)
+    .bind(&secret)
+    .fetch_one(&mut *tx)
+    .await?;
+    // Past the events of the transactions already finished, see the outbox migration.
+    sqlx::query(
+        "INSERT INTO outbox_cursors (sink, txid) VALUES ($1, pg_snapshot_xmin(pg_current_snapshot()));",
+    )
+    .bind(cursor_name(&id))
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(WebhookSubscription {
+        secret: Some(secret),
+        ..to_reply(row)
+    })
+}
+
+pub(crate) async fn list_subscriptions(
+    db: &PgPool,
+) -> Result<Vec<WebhookSubscription>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, SubscriptionRow>(
+        "SELECT id, url, enabled, consecutive_failures, created_at FROM webhook_subscriptions ORDER BY id;",
+    )
+    .fetch_all(db)
+    .await?;
+    Ok(rows.into_iter().map(to_reply).collect())
+}
+
+/// Enables a subscription again, resuming from the first event it failed to deliver.
+pub(crate) async fn enable_subscription(
+    db: &PgPool,
+    id: Uuid,
+) -> Result<Option<WebhookSubscription>, sqlx::Error> {
+    let row = sqlx::query_as::<_, SubscriptionRow>(
+        "UPDATE webhook_subscriptions SET enabled = TRUE, consecutive_failures = 0, next_attempt_at = NOW() WHERE id = $1 RETURNING id, url, enabled, consecutive_failures, created_at;",
+    )
+    .bind(id)
+    .fetch_optional(db)
+    .await?;
+    Ok(row.map(to_reply))
+}
+
+/// Returns whether the subscription existed, its deliveries are deleted along with it.
+pub(crate) async fn delete_subscription(db: &PgPool, id: Uuid) -> Result<bool, sqlx::Error> {
+    let mut tx = db.begin().await?;
+    let deleted = sqlx::query("DELETE FROM webhook_subscriptions WHERE id = $1;")
+        .bind(id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    sqlx::query("DELETE FROM outbox_cursors WHERE sink = $1;")
+        .bind(cursor_name(&id))
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+    Ok(deleted > 0)
+}
+
+/// The most recent deliveries of the subscription first.
+pub(crate) async fn list_deliveries(
+    db: &PgPool,
+    subscription_id: Uuid,
+    limit: i64,
+) -> Result<Vec<WebhookDelivery>, sqlx::Error> {
+    let rows = sqlx::query_as::<
+        _,
+        (i64, Uuid, i64, i64, i32, Option<i32>, Option<String>, i64, DateTime<Utc>),
+    >(
+        "SELECT id, subscription_id, first_seq, last_seq, events, status_code, error, duration_ms, delivered_at FROM webhook_deliveries WHERE subscription_id = $1 ORDER BY id DESC LIMIT $2;",
+    )
+    .bind(subscription_id)
+    .bind(limit)
+    .fetch_all(db)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(
+            |(
+                id,
+                subscription_id,
+                first_seq,
+                last_seq,
+                events,
+                status_code,
+                error,
+                duration_ms,
+                delivered_at,
+            )| {
+                WebhookDelivery {
+                    id,
+                    subscription_id: subscription_id.to_string(),
+                    first_seq,
+                    last_seq,
+                    events: events as u32,
+                    status_code: status_code.map(|status_code| status_code as u32),
+                    error,
+                    duration_ms: duration_ms as u64,
+                    delivered_at: delivered_at.to_rfc3339(),
+                }
+            },
+        )
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_the_internal_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{ip}");
+        }
+        for ip in ["8.8.8.8", "100.128.0.1", "2606:4700::1111"] {
+            assert!(is_public(ip.parse().unwrap()), "{ip}");
+        }
+    }
+
+    #[test]
+    fn checks_the_urls_with_an_address() {
+        assert!(has_private_address("http://127.0.0.1:8080/hook"));
+        assert!(has_private_address("http://[::1]/hook"));
+        assert!(!has_private_address("https://8.8.8.8/hook"));
+        assert!(!has_private_address("https://example.com/hook"));
+    }
+}
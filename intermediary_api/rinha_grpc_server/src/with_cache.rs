@@ -1,85 +1,417 @@
 use dashmap::{DashMap, DashSet};
-use sqlx::{postgres::PgPoolOptions, PgPool};
-use tokio::sync::mpsc::{self, UnboundedReceiver};
+use futures::TryStreamExt;
+use sqlx::PgPool;
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    oneshot, Semaphore,
+};
 use tonic::{transport::Server, Request, Response, Status};
 use tonic_tracing_opentelemetry::middleware::server;
 use tower_http::trace::TraceLayer;
+use uuid::{Builder, Uuid};
 
 use crate::{
-    models::pessoa::Pessoa,
+    admin::MyRinhaAdmin,
+    batch::{
+        batch_insert_task, insert_pessoas, BatchMetrics, BatchQueue, InsertOutcome, QueuedPessoa,
+    },
+    batch_controller::BatchController,
+    cache::{BoundedCache, SearchCache},
+    consistency::ConsistencyTokens,
+    counter::PessoaCounter,
+    dead_letter::DeadLetters,
+    event_sink,
+    listener::{self, listen_task},
+    migrations,
+    models::pessoa::{pessoa_by_id, search_filtered, search_pessoas, Pessoa, PESSOA_SEARCH_LIMIT},
+    outbox::relay_task,
+    pool::{self, PoolLimit},
+    query_monitor::{query_status, QueryMonitor},
+    read_pools::ReadPools,
     rinha::{
         self,
+        rinha_admin_server::RinhaAdminServer,
         rinha_server::{Rinha, RinhaServer},
-        CountPessoaReply, CountPessoaRequest, CreatePessoaReply, CreatePessoaRequest,
-        PessoaByIdRequest, PessoaReply, PessoaSearchReply, PessoaSearchRequest,
+        CountConsistency, CountPessoaReply, CountPessoaRequest, CreatePessoaReply,
+        CreatePessoaRequest, PessoaByIdRequest, PessoaReply, PessoaSearchReply,
+        PessoaSearchRequest, SearchMode,
     },
+    search_index::SearchIndex,
+    singleflight::Singleflight,
+    snapshot,
     utils::{
-        env::{EnvironmentValues, LoggerOutput},
-        telemetry,
+        env::{BatchInsertMethod, CacheWarmupConfig, EnvironmentValues, LoggerOutput, WalConfig},
+        signal, telemetry,
     },
+    wal::Wal,
+    webhooks::{self, webhooks_task},
 };
-use std::{sync::Arc, time::Duration};
+use std::{collections::HashSet, sync::Arc, time::Duration};
 
-use tokio::select;
+/// How far before a snapshot was written the pessoas are loaded from the database.
+const SNAPSHOT_CLOCK_SKEW_SECS: i64 = 60;
 
 pub struct MyRinha {
     pub pessoa_by_apelido_exists_set: DashSet<String>,
-    pub pessoa_by_id_map: DashMap<String, String>,
-    pub pessoa_search_map: DashMap<String, String>,
-    pub pessoa_sender: mpsc::UnboundedSender<Pessoa>,
+    /// Committed pessoas only, as its entries may be evicted at any time.
+    pub pessoa_by_id_map: BoundedCache,
+    /// Pessoas queued but not committed yet, which the database can't answer
+    /// for, so they are never evicted. They are moved to `pessoa_by_id_map`
+    /// once their batch settles.
+    pub pessoa_pending_map: DashMap<String, String>,
+    /// Checked after `pessoa_by_id_map`, which wins when an id is in both.
+    pub pessoa_not_found_map: Option<BoundedCache>,
+    pub pessoa_search_map: SearchCache,
+    pub pessoa_search_index: Option<SearchIndex>,
+    /// Coalesce the concurrent cache misses of the same id or term into a single query.
+    pub pessoa_by_id_flights: Singleflight<Result<Option<String>, Status>>,
+    pub pessoa_search_flights: Singleflight<Result<String, Status>>,
+    /// Default minimum similarity of the ranked searches.
+    pub similarity_threshold: f32,
+    pub pessoa_sender: mpsc::Sender<QueuedPessoa>,
+    pub flush_sender: mpsc::UnboundedSender<oneshot::Sender<()>>,
+    pub pessoa_count: PessoaCounter,
+    pub consistency_tokens: ConsistencyTokens,
+    /// Bounds the amount of batch insert transactions running at once.
+    pub batch_insert_permits: Arc<Semaphore>,
+    /// Seconds a client is told to wait when a pessoa is shed due to the queue being full.
+    pub retry_after_secs: u64,
+    pub batch_insert_method: BatchInsertMethod,
+    /// Whether the batches write the events of the pessoas to the outbox.
+    pub outbox: bool,
+    pub wal: Option<Wal>,
+    pub dead_letters: DeadLetters,
+    pub batch_metrics: BatchMetrics,
+    pub batch_controller: BatchController,
     pub db: PgPool,
+    /// Where `pessoa_by_id` and `pessoa_search` read from. The pessoa counter is
+    /// reconciled with the primary, as it has to see the commits right away.
+    pub read_pools: ReadPools,
+    pub queries: QueryMonitor,
+    /// Bounds the connections taken by the reads and the batch inserts, adjusted by `SetPoolLimit`.
+    pub pool_limit: Arc<PoolLimit>,
 }
 
 impl MyRinha {
     pub async fn from(
         env_values: &EnvironmentValues,
-    ) -> Result<(Self, UnboundedReceiver<Pessoa>), Box<dyn std::error::Error>> {
-        let db = PgPoolOptions::new()
-            .max_connections(env_values.db_pool_max_size)
-            .connect(&env_values.database_url)
+    ) -> Result<(Self, BatchQueue), Box<dyn std::error::Error>> {
+        let mut connect_options = env_values.database_url.parse()?;
+        if env_values.cache_coherency.is_some() {
+            connect_options = listener::notifying(connect_options);
+        }
+        let db = pool::connect(connect_options, &env_values.database_pool).await?;
+        migrations::prepare(&db, env_values).await?;
+        let (mut rinha, queue) = Self::new(db, env_values)?;
+        rinha
+            .read_pools
+            .check_capacity(&env_values.database_pool)
             .await?;
-        let (pessoa_sender, pessoa_receiver) = mpsc::unbounded_channel();
-        Ok((
-            Self {
-                db,
-                pessoa_sender,
-                pessoa_by_apelido_exists_set: Default::default(),
-                pessoa_by_id_map: Default::default(),
-                pessoa_search_map: Default::default(),
-            },
-            pessoa_receiver,
-        ))
+        if let Some(config) = env_values.wal.as_ref() {
+            rinha.replay_wal(config, env_values).await?;
+            rinha.wal = Some(Wal::open(config)?);
+        }
+        // Under the count timeout, which may be too short for the seed, the periodic reconcile fixes it later.
+        if let Err(err) = rinha
+            .pessoa_count
+            .reconcile(rinha.read_pools.primary(), &rinha.queries)
+            .await
+        {
+            tracing::warn!(message = "Failed to seed the pessoa counter.", %err);
+        }
+        rinha.warm_up(&env_values.cache_warmup).await?;
+        Ok((rinha, queue))
+    }
+
+    /// The caches start empty and the write ahead log closed.
+    fn new(
+        db: PgPool,
+        env_values: &EnvironmentValues,
+    ) -> Result<(Self, BatchQueue), Box<dyn std::error::Error>> {
+        let read_pools = ReadPools::new(
+            db.clone(),
+            &env_values.database_read_replicas,
+            &env_values.database_pool,
+        )?;
+        let pool_limit = Arc::new(PoolLimit::new(
+            &env_values.database_pool,
+            read_pools.named_pools(),
+        ));
+        let (pessoa_sender, pessoa_receiver) = mpsc::channel(env_values.batch_queue_capacity);
+        let (flush_sender, flushes) = mpsc::unbounded_channel();
+        let batch_metrics = BatchMetrics::new(&pessoa_sender);
+        let rinha = Self {
+            db,
+            read_pools,
+            queries: QueryMonitor::new(&env_values.database_queries, pool_limit.clone()),
+            pool_limit,
+            pessoa_sender,
+            flush_sender,
+            pessoa_count: Default::default(),
+            consistency_tokens: ConsistencyTokens::new(env_values.consistency_token_timeout),
+            batch_insert_permits: Arc::new(Semaphore::new(env_values.batch_max_concurrent_inserts)),
+            retry_after_secs: env_values.batch_queue_retry_after_secs,
+            batch_insert_method: env_values.batch_insert_method,
+            outbox: env_values.writes_events(),
+            wal: None,
+            dead_letters: DeadLetters::new(env_values.dead_letter_file.clone()),
+            batch_metrics,
+            batch_controller: BatchController::new(env_values),
+            pessoa_by_apelido_exists_set: Default::default(),
+            pessoa_by_id_map: BoundedCache::new("pessoa_by_id", &env_values.pessoa_by_id_cache),
+            pessoa_pending_map: Default::default(),
+            pessoa_not_found_map: env_values
+                .pessoa_not_found_cache
+                .as_ref()
+                .map(|config| BoundedCache::new("pessoa_not_found", config)),
+            pessoa_search_map: SearchCache::new(&env_values.pessoa_search_cache),
+            pessoa_search_index: env_values.pessoa_search_index.then(SearchIndex::default),
+            pessoa_by_id_flights: Singleflight::new("pessoa_by_id"),
+            pessoa_search_flights: Singleflight::new("pessoa_search"),
+            similarity_threshold: env_values.pessoa_search_similarity_threshold,
+        };
+        let queue = BatchQueue {
+            pessoas: pessoa_receiver,
+            flushes,
+        };
+        Ok((rinha, queue))
+    }
+
+    /// Stores the pessoas left at the write ahead log by a previous execution
+    /// like the batches, retrying and dead lettering them, so a pessoa that
+    /// keeps failing does not prevent the startup. The log is kept for the next
+    /// one when they can't be stored anywhere.
+    async fn replay_wal(
+        &self,
+        config: &WalConfig,
+        env_values: &EnvironmentValues,
+    ) -> Result<(), Box<dyn std::error::Error>> {
+        let pessoas = Wal::replay(&config.dir)?;
+        let (mut inserted, mut dead_lettered) = (0, 0);
+        for chunk in pessoas.chunks(env_values.batch_max_insert_size) {
+            let outcome = insert_pessoas(self, chunk.to_vec(), &env_values.batch_retry).await;
+            if !outcome.settled {
+                return Err("Failed to store the pessoas of the write ahead log".into());
+            }
+            inserted += outcome.inserted.len();
+            dead_lettered += outcome.dead_lettered.len();
+        }
+        tracing::info!(
+            message = "Replayed the write ahead log.",
+            amount = pessoas.len(),
+            inserted,
+            dead_lettered
+        );
+        Ok(())
+    }
+
+    /// Loads the cache snapshot, when there is one, and then streams the pessoas
+    /// stored at the database, the most recent ones first, into the id cache and
+    /// the apelido registry until the memory budget is taken.
+    ///
+    /// The search index has to be complete to answer searches without the
+    /// database, so it gets every pessoa regardless of the budget.
+    async fn warm_up(&self, config: &CacheWarmupConfig) -> Result<(), sqlx::Error> {
+        let written_at = match config.snapshot_file.as_ref() {
+            Some(path) => snapshot::load(path, self).await.unwrap_or_else(|err| {
+                tracing::warn!(message = "Failed to load the cache snapshot.", %err);
+                None
+            }),
+            None => None,
+        };
+        if !config.enabled && self.pessoa_search_index.is_none() {
+            return Ok(());
+        }
+        let mut pessoas = match written_at.filter(|_| self.pessoa_search_index.is_none()) {
+            // Only the pessoas created since the snapshot are missing, give or take the clocks
+            // of the other instances.
+            Some(written_at) => {
+                let since = written_at - chrono::Duration::seconds(SNAPSHOT_CLOCK_SKEW_SECS);
+                let since = since.timestamp_millis().max(0);
+                let first_id = Builder::from_unix_timestamp_millis(since as u64, &[0; 10]);
+                sqlx::query_as::<_, Pessoa>(
+                    "SELECT id, apelido, nome, nascimento, stack FROM pessoas WHERE id >= $1 AND pessoa_created_at(id) IS NOT NULL ORDER BY id DESC;",
+                )
+                .bind(first_id.into_uuid())
+                .fetch(&self.db)
+            }
+            None => sqlx::query_as::<_, Pessoa>(
+                "SELECT id, apelido, nome, nascimento, stack FROM pessoas ORDER BY id DESC;",
+            )
+            .fetch(&self.db),
+        };
+        let (mut bytes, mut cached, mut indexed) = (0, 0, 0);
+        let mut within_budget = config.enabled;
+        while let Some(pessoa) = pessoas.try_next().await? {
+            let json = serde_json::to_string(&pessoa).unwrap();
+            let id = pessoa.id.to_string();
+            let size = (id.len() + json.len() + pessoa.apelido.len()) as u64;
+            within_budget &= bytes + size <= config.max_bytes;
+            if within_budget {
+                bytes += size;
+                cached += 1;
+                self.pessoa_by_id_map.insert(id, json.clone());
+                self.pessoa_by_apelido_exists_set
+                    .insert(pessoa.apelido.clone());
+            } else if self.pessoa_search_index.is_none() {
+                break;
+            }
+            if self.pessoa_search_index.is_some() {
+                self.index_pessoa(&pessoa, &json);
+                indexed += 1;
+            }
+        }
+        tracing::info!(message = "Warmed up the caches.", cached, bytes, indexed);
+        Ok(())
+    }
+
+    fn index_pessoa(&self, pessoa: &Pessoa, json: &str) {
+        self.pessoa_by_apelido_exists_set
+            .insert(pessoa.apelido.clone());
+        if let Some(index) = self.pessoa_search_index.as_ref() {
+            index.insert(pessoa, json);
+        }
+    }
+
+    /// Takes in a pessoa committed by another instance, returning whether it was unknown.
+    pub(crate) fn committed_elsewhere(&self, pessoa: &Pessoa) -> bool {
+        // The pessoas created here, or already taken in, have their apelido reserved.
+        if self.pessoa_by_apelido_exists_set.contains(&pessoa.apelido) {
+            return false;
+        }
+        let json = serde_json::to_string(pessoa).unwrap();
+        self.index_pessoa(pessoa, &json);
+        self.pessoa_search_map
+            .invalidate_committed([pessoa.busca().as_str()]);
+        self.insert_pessoa_by_id(pessoa.id.to_string(), json);
+        true
+    }
+
+    /// Caches a pessoa, an id that was not found could be of it.
+    fn insert_pessoa_by_id(&self, id: String, json: String) {
+        self.pessoa_by_id_map.insert(id.clone(), json);
+        if let Some(not_found) = self.pessoa_not_found_map.as_ref() {
+            not_found.remove(&id);
+        }
+    }
+
+    fn cached_pessoa_by_id(&self, id: &str) -> Option<String> {
+        match self.pessoa_pending_map.get(id) {
+            Some(json) => Some(json.clone()),
+            None => self.pessoa_by_id_map.get(id),
+        }
+    }
+
+    /// Caches an id that was not found by a query started at `epoch`, unless a
+    /// pessoa with it was created or committed meanwhile.
+    fn insert_not_found(&self, id: &str, epoch: u64) {
+        if let Some(not_found) = self.pessoa_not_found_map.as_ref() {
+            not_found.insert(id.to_owned(), String::new());
+            // Checked after the insert, as a concurrent create or commit checks this cache after its own.
+            if self.pessoa_search_map.epoch() != epoch
+                || self.pessoa_pending_map.contains_key(id)
+                || self.pessoa_by_id_map.contains_key(id)
+            {
+                not_found.remove(id);
+            }
+        }
+    }
+
+    /// Moves the pessoas of a settled batch out of `pessoa_pending_map`, the
+    /// inserted ones into `pessoa_by_id_map`. The others are taken out of the
+    /// search index, and the dead lettered ones give their apelido back, unlike
+    /// the ones skipped due to a conflict, whose apelido is taken at the database.
+    ///
+    /// Must follow the epoch bump of [`SearchCache::invalidate_committed`],
+    /// which [`Self::insert_not_found`] relies on.
+    pub(crate) fn settle_pending(&self, ids: &[Uuid], outcome: &InsertOutcome) {
+        let inserted: HashSet<Uuid> = outcome.inserted.iter().map(|pessoa| pessoa.id).collect();
+        for id in ids {
+            let key = id.to_string();
+            let pending = self.pessoa_pending_map.remove(&key);
+            if inserted.contains(id) {
+                if let Some((key, json)) = pending {
+                    self.pessoa_by_id_map.insert(key, json);
+                }
+            } else if let Some(index) = self.pessoa_search_index.as_ref() {
+                index.remove(id);
+            }
+            if let Some(not_found) = self.pessoa_not_found_map.as_ref() {
+                not_found.remove(&key);
+            }
+        }
+        for pessoa in outcome.dead_lettered.iter() {
+            self.pessoa_by_apelido_exists_set.remove(&pessoa.apelido);
+        }
+    }
+
+    /// Takes in the pessoas a replay of the dead letters inserted, which unlike
+    /// the ones of a batch were never pending, like [`Self::committed_elsewhere`].
+    pub(crate) fn settle_replayed(&self, outcome: &InsertOutcome) {
+        let buscas: Vec<String> = outcome.inserted.iter().map(Pessoa::busca).collect();
+        self.pessoa_search_map
+            .invalidate_committed(buscas.iter().map(String::as_str));
+        for pessoa in outcome.inserted.iter() {
+            let json = serde_json::to_string(pessoa).unwrap();
+            self.index_pessoa(pessoa, &json);
+            self.insert_pessoa_by_id(pessoa.id.to_string(), json);
+        }
     }
 }
 
+fn write_not_committed() -> Status {
+    Status::deadline_exceeded("The pessoa of the consistency token is not committed yet")
+}
+
 #[tonic::async_trait]
 impl Rinha for MyRinha {
     async fn pessoa_by_id(
         &self,
         request: Request<PessoaByIdRequest>,
     ) -> Result<Response<PessoaReply>, Status> {
-        if let Some(json) = self
-            .pessoa_by_id_map
-            .get(&request.get_ref().id)
-            .map(|json| json.clone())
-        {
+        if let Some(json) = self.cached_pessoa_by_id(&request.get_ref().id) {
             Ok(Response::new(PessoaReply { json: Some(json) }))
         } else {
-            let json = sqlx::query_as::<_, Pessoa>(
-                "
-        SELECT * FROM pessoas where id = $1;
-    ",
-            )
-            .bind(request.get_ref().id.as_str())
-            .fetch_one(&self.db)
-            .await
-            .map(|pessoa| serde_json::to_string(&pessoa).ok())
-            .ok()
-            .flatten();
-            if let Some(json) = json.as_ref() {
-                self.pessoa_by_id_map
-                    .insert(request.get_ref().id.clone(), json.clone());
+            // Not an id of any pessoa.
+            let Ok(id) = Uuid::parse_str(&request.get_ref().id) else {
+                return Ok(Response::new(PessoaReply { json: None }));
+            };
+            let key = &request.get_ref().id;
+            let token = request.get_ref().consistency_token.as_deref();
+            if let Some(token) = token {
+                // Its pessoa may have been evicted from the cache, or not found before it was committed.
+                if !self.consistency_tokens.wait(token).await {
+                    return Err(write_not_committed());
+                }
+            } else {
+                let not_found = self.pessoa_not_found_map.as_ref();
+                if not_found.is_some_and(|not_found| not_found.get(key).is_some()) {
+                    return Ok(Response::new(PessoaReply { json: None }));
+                }
             }
+            let query = || async {
+                let epoch = self.pessoa_search_map.epoch();
+                let db = self.read_pools.pool_for_id(&id);
+                let pessoa = pessoa_by_id(db, id, &self.queries).await;
+                match pessoa {
+                    Ok(Some(pessoa)) => {
+                        let json = serde_json::to_string(&pessoa).unwrap();
+                        self.pessoa_by_id_map.insert(key.clone(), json.clone());
+                        Ok(Some(json))
+                    }
+                    // Only an id surely not found is cached, not one that failed to be read.
+                    Ok(None) => {
+                        self.insert_not_found(key, epoch);
+                        Ok(None)
+                    }
+                    Err(err) => Err(query_status(&err)),
+                }
+            };
+            // A query that was already running when the pessoa of the token was committed could miss it.
+            let json = match token {
+                Some(_) => query().await,
+                None => self.pessoa_by_id_flights.run(key, query).await,
+            }?;
             Ok(Response::new(PessoaReply { json }))
         }
     }
@@ -88,25 +420,65 @@ impl Rinha for MyRinha {
         &self,
         request: Request<PessoaSearchRequest>,
     ) -> Result<Response<PessoaSearchReply>, Status> {
-        let term = request.into_inner().term;
-        if let Some(json) = self.pessoa_search_map.get(&term) {
-            return Ok(Response::new(PessoaSearchReply {
-                json: Some(json.clone()),
-            }));
+        let mut request = request.into_inner();
+        let token = request.consistency_token.take();
+        // The replicas could still miss the pessoa of the token.
+        let db = match token {
+            Some(_) => self.read_pools.primary(),
+            None => self.read_pools.pool(),
+        };
+        // Neither the index nor the cache know about these filters nor the ranking.
+        if request.stack.is_some()
+            || request.desde.is_some()
+            || request.mode() != SearchMode::Substring
+        {
+            if let Some(token) = token.as_deref() {
+                if !self.consistency_tokens.wait(token).await {
+                    return Err(write_not_committed());
+                }
+            }
+            let json = search_filtered(db, &request, self.similarity_threshold, &self.queries)
+                .await
+                .map_err(|err| query_status(&err))?;
+            return Ok(Response::new(PessoaSearchReply { json: Some(json) }));
         }
-        let term_param = format!("%{}%", term);
-        let search_res = sqlx::query_as::<sqlx::Postgres, Pessoa>(
-            "
-            SELECT id, apelido, nome, nascimento, stack FROM pessoas p where p.busca_trgm LIKE $1 LIMIT 50;
-        ",
-        )
-        .bind(&term_param)
-        .persistent(true)
-        .fetch_all(&self.db).await.ok().map(|res| serde_json::to_string(&res).ok()).flatten();
-        if let Some(search_res) = search_res.as_ref() {
-            self.pessoa_search_map.insert(term, search_res.clone());
+        let term = request.term;
+        if let Some((_, json)) = self
+            .pessoa_search_index
+            .as_ref()
+            .and_then(|index| index.search(&term, PESSOA_SEARCH_LIMIT))
+        {
+            return Ok(Response::new(PessoaSearchReply { json: Some(json) }));
         }
-        Ok(Response::new(PessoaSearchReply { json: search_res }))
+        // Unlike the index, the cache and the database don't know about the queued pessoas.
+        if let Some(token) = token.as_deref() {
+            if !self.consistency_tokens.wait(token).await {
+                return Err(write_not_committed());
+            }
+        }
+        if let Some(json) = self.pessoa_search_map.get(&term) {
+            return Ok(Response::new(PessoaSearchReply { json: Some(json) }));
+        }
+        let query = || async {
+            let epoch = self.pessoa_search_map.epoch();
+            let search_res = search_pessoas(db, &term, &self.queries)
+                .await
+                .map_err(|err| query_status(&err))?;
+            let json = serde_json::to_string(&search_res).unwrap();
+            // A lagging replica could miss committed pessoas, which the epoch does not tell.
+            if !self.read_pools.has_replicas() {
+                self.pessoa_search_map
+                    .insert(term.clone(), json.clone(), search_res.len(), epoch);
+            }
+            Ok(json)
+        };
+        let search_res = match token {
+            Some(_) => query().await,
+            None => self.pessoa_search_flights.run(&term, query).await,
+        }?;
+        Ok(Response::new(PessoaSearchReply {
+            json: Some(search_res),
+        }))
     }
 
     async fn create_pessoa(
@@ -118,98 +490,96 @@ impl Rinha for MyRinha {
             return Ok(Response::new(CreatePessoaReply {
                 id: None,
                 status: 422,
+                consistency_token: None,
             }));
         }
         if let Some(pessoa) = Pessoa::from(request) {
-            let id = pessoa.id.clone();
-            self.pessoa_by_id_map
-                .insert(id.clone(), serde_json::to_string(&pessoa).unwrap());
-            self.pessoa_by_apelido_exists_set.insert(id.clone());
-            let _ = self.pessoa_sender.send(pessoa);
+            let permit = match self.pessoa_sender.try_reserve() {
+                Ok(permit) => permit,
+                Err(TrySendError::Full(_)) => {
+                    self.batch_metrics.shed();
+                    let mut status = Status::resource_exhausted("Insert queue is full");
+                    status
+                        .metadata_mut()
+                        .insert("retry-after", self.retry_after_secs.into());
+                    return Err(status);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    return Err(Status::unavailable("Insert queue is closed"))
+                }
+            };
+            // Reserving the apelido is what prevents two concurrent requests from both succeeding.
+            if !self
+                .pessoa_by_apelido_exists_set
+                .insert(pessoa.apelido.clone())
+            {
+                return Ok(Response::new(CreatePessoaReply {
+                    id: None,
+                    status: 422,
+                    consistency_token: None,
+                }));
+            }
+            let wal_segment = match self.wal.as_ref() {
+                Some(wal) => match wal.append(&pessoa).await {
+                    Ok(segment) => Some(segment),
+                    Err(err) => {
+                        tracing::error!(message = "Failed to append to the write ahead log.", %err);
+                        self.pessoa_by_apelido_exists_set.remove(&pessoa.apelido);
+                        return Err(Status::unavailable("Write ahead log unavailable"));
+                    }
+                },
+                None => None,
+            };
+            let id = pessoa.id.to_string();
+            let json = serde_json::to_string(&pessoa).unwrap();
+            self.index_pessoa(&pessoa, &json);
+            self.pessoa_search_map.invalidate([pessoa.busca().as_str()]);
+            self.pessoa_pending_map.insert(id.clone(), json);
+            if let Some(not_found) = self.pessoa_not_found_map.as_ref() {
+                not_found.remove(&id);
+            }
+            self.pessoa_count.enqueued();
+            let seq = self.consistency_tokens.queued();
+            permit.send(QueuedPessoa {
+                pessoa,
+                wal_segment,
+                seq,
+            });
             Ok(Response::new(CreatePessoaReply {
                 id: Some(id),
                 status: 201,
+                consistency_token: Some(self.consistency_tokens.token(seq)),
             }))
         } else {
             Ok(Response::new(CreatePessoaReply {
                 id: None,
                 status: 400,
+                consistency_token: None,
             }))
         }
     }
 
     async fn count_pessoa(
         &self,
-        _: Request<CountPessoaRequest>,
+        request: Request<CountPessoaRequest>,
     ) -> Result<Response<CountPessoaReply>, Status> {
-        let amount = sqlx::query_as::<_, (i64,)>("SELECT COUNT(id) FROM pessoas;")
-            .fetch_one(&self.db)
-            .await;
-        match amount {
-            Ok(amount) => Ok(Response::new(CountPessoaReply {
-                amount: amount.0 as u64,
-            })),
-            _ => Err(Status::unavailable("Internal server error")),
-        }
-    }
-}
-
-fn batch_insert(pessoas_to_insert: &mut Vec<Pessoa>, db: &PgPool) {
-    if pessoas_to_insert.len() > 0 {
-        let mut query = sqlx::QueryBuilder::<sqlx::Postgres>::new(
-            "INSERT INTO pessoas (id, nome, apelido, nascimento, stack) ",
-        );
-        query.push_values(pessoas_to_insert.drain(..), |mut b, pessoa| {
-            b.push_bind(pessoa.id)
-                .push_bind(pessoa.nome)
-                .push_bind(pessoa.apelido)
-                .push_bind(pessoa.nascimento)
-                .push_bind(pessoa.stack.map(|stacks| stacks.join(" ")));
-        });
-        query.push(" ON CONFLICT DO NOTHING;");
-        let db = db.clone();
-        tokio::spawn(async move {
-            if let Ok(mut tx) = db.begin().await {
-                let _ = if query.build().execute(&mut *tx).await.is_ok() {
-                    tx.commit().await
-                } else {
-                    tx.rollback().await
-                };
-            }
-        });
-    }
-}
-
-enum PessoaOrTimeout {
-    ReceiverClosed,
-    Timeout,
-    Pessoa(Pessoa),
-}
-
-pub async fn batch_insert_task(
-    mut pessoa_receiver: UnboundedReceiver<Pessoa>,
-    db: PgPool,
-    env_values: Arc<EnvironmentValues>,
-) {
-    let mut pessoas_to_insert = Vec::with_capacity(env_values.batch_max_insert_size);
-    loop {
-        let pessoa_fut = pessoa_receiver.recv();
-        let sleep_fut = tokio::time::sleep(Duration::from_secs(
-            env_values.batch_max_wait_on_insert_channel,
-        ));
-        match select! {
-            pessoa = pessoa_fut => pessoa.map(PessoaOrTimeout::Pessoa).unwrap_or(PessoaOrTimeout::ReceiverClosed),
-            _ = sleep_fut => PessoaOrTimeout::Timeout,
-        } {
-            PessoaOrTimeout::Pessoa(pessoa) => {
-                pessoas_to_insert.push(pessoa);
-                if pessoas_to_insert.len() == env_values.batch_max_insert_size {
-                    batch_insert(&mut pessoas_to_insert, &db)
-                }
+        let amount = match request.into_inner().consistency() {
+            CountConsistency::Fast => self.pessoa_count.get(),
+            CountConsistency::Exact => {
+                let (flush, flushed) = oneshot::channel();
+                self.flush_sender
+                    .send(flush)
+                    .map_err(|_| Status::unavailable("Insert queue is closed"))?;
+                flushed
+                    .await
+                    .map_err(|_| Status::unavailable("Insert queue is closed"))?;
+                self.pessoa_count
+                    .reconcile(self.read_pools.primary(), &self.queries)
+                    .await
+                    .map_err(|err| query_status(&err))?
             }
-            PessoaOrTimeout::Timeout => batch_insert(&mut pessoas_to_insert, &db),
-            PessoaOrTimeout::ReceiverClosed => break,
-        }
+        };
+        Ok(Response::new(CountPessoaReply { amount }))
     }
 }
 
@@ -225,9 +595,29 @@ pub async fn server() -> Result<(), Box<dyn std::error::Error>> {
     let reflection_service = tonic_reflection::server::Builder::configure()
         .register_encoded_file_descriptor_set(rinha::FILE_DESCRIPTOR_SET)
         .build()?;
-    let (rinha_svc, pessoa_receiver) = MyRinha::from(&env_values).await?;
+    let (rinha_svc, batch_queue) = MyRinha::from(&env_values).await?;
+    let rinha_svc = Arc::new(rinha_svc);
+    let admin_svc = MyRinhaAdmin {
+        rinha: rinha_svc.clone(),
+        env_values: env_values.clone(),
+    };
+    rinha_svc
+        .read_pools
+        .spawn_health_checks(env_values.database_read_replicas.health_check_interval);
+    if let Some(config) = env_values.cache_coherency.clone() {
+        tokio::spawn(listen_task(rinha_svc.clone(), config));
+    }
+    if let Some(config) = env_values.outbox.clone() {
+        let sink = event_sink::from_config(&config.sink)?;
+        tokio::spawn(relay_task(rinha_svc.db.clone(), sink, config));
+    }
+    if let Some(config) = env_values.webhooks.clone() {
+        let client = webhooks::client(&config)?;
+        tokio::spawn(webhooks_task(rinha_svc.db.clone(), client, config));
+    }
     let db_pool = rinha_svc.db.clone();
-    tokio::spawn(async move {
+    let mut health_reporter_on_shutdown = health_reporter.clone();
+    let health_task = tokio::spawn(async move {
         loop {
             tokio::time::sleep(Duration::from_secs(1)).await;
             if db_pool.acquire().await.is_ok() {
@@ -239,43 +629,205 @@ pub async fn server() -> Result<(), Box<dyn std::error::Error>> {
             }
         }
     });
+    let (rinha, interval) = (rinha_svc.clone(), env_values.count_reconcile_interval);
+    tokio::spawn(async move {
+        loop {
+            tokio::time::sleep(interval).await;
+            if let Err(err) = rinha
+                .pessoa_count
+                .reconcile(rinha.read_pools.primary(), &rinha.queries)
+                .await
+            {
+                tracing::warn!(message = "Failed to reconcile the pessoa counter.", %err);
+            }
+        }
+    });
     tracing::info!(message = "Starting server.", %addr);
-    tokio::spawn(batch_insert_task(
-        pessoa_receiver,
-        rinha_svc.db.clone(),
+    let snapshot_rinha = rinha_svc.clone();
+    let (shutdown_sender, shutdown_receiver) = oneshot::channel();
+    let batch_task = tokio::spawn(batch_insert_task(
+        batch_queue,
+        rinha_svc.clone(),
         env_values.clone(),
+        shutdown_receiver,
     ));
+    // Stops accepting requests and waits for the ones in flight.
+    let shutdown = async move {
+        signal::shutdown().await;
+        health_task.abort();
+        health_reporter_on_shutdown
+            .set_not_serving::<RinhaServer<MyRinha>>()
+            .await;
+    };
     match env_values.logger {
         Some(LoggerOutput::Otel) => {
             Server::builder()
                 .layer(server::OtelGrpcLayer::default())
-                .add_service(RinhaServer::new(rinha_svc))
+                .add_service(RinhaServer::from_arc(rinha_svc))
+                .add_service(RinhaAdminServer::new(admin_svc))
                 .add_service(health_service)
                 .add_service(reflection_service)
-                .serve(addr)
+                .serve_with_shutdown(addr, shutdown)
                 .await?
         }
         Some(LoggerOutput::Stdout) => {
             Server::builder()
                 .layer(TraceLayer::new_for_grpc())
-                .add_service(RinhaServer::new(rinha_svc))
+                .add_service(RinhaServer::from_arc(rinha_svc))
+                .add_service(RinhaAdminServer::new(admin_svc))
                 .add_service(health_service)
                 .add_service(reflection_service)
-                .serve(addr)
+                .serve_with_shutdown(addr, shutdown)
                 .await?
         }
         None => {
             Server::builder()
                 .add_service(health_service)
                 .add_service(reflection_service)
-                .add_service(RinhaServer::new(rinha_svc))
-                .serve(addr)
+                .add_service(RinhaServer::from_arc(rinha_svc))
+                .add_service(RinhaAdminServer::new(admin_svc))
+                .serve_with_shutdown(addr, shutdown)
                 .await?
         }
     }
-    // Ensure all spans have been shipped.
+    // No request is being served anymore, so the queue only has to be flushed.
+    let _ = shutdown_sender.send(());
+    if tokio::time::timeout(env_values.shutdown_timeout, batch_task)
+        .await
+        .is_err()
+    {
+        tracing::error!("Timed out flushing the insert queue, the pending pessoas may be lost.");
+    }
+    // Written once every queued pessoa is committed, so a snapshot never holds an uncommitted one.
+    if let Some(path) = env_values.cache_warmup.snapshot_file.clone() {
+        match tokio::task::spawn_blocking(move || snapshot::write(&path, &snapshot_rinha)).await {
+            Ok(Ok(amount)) => tracing::info!(message = "Wrote the cache snapshot.", amount),
+            Ok(Err(err)) => tracing::error!(message = "Failed to write the cache snapshot.", %err),
+            Err(err) => tracing::error!(message = "Failed to write the cache snapshot.", %err),
+        }
+    }
+    // Ensure all spans and metrics have been shipped.
     if let Some(LoggerOutput::Otel) = env_values.logger {
-        opentelemetry::global::shutdown_tracer_provider();
+        telemetry::shutdown_otel();
     }
     Ok(())
 }
+
+#[cfg(test)]
+impl MyRinha {
+    /// A server over `db` with the default settings and empty caches.
+    pub(crate) fn for_tests(db: PgPool) -> Self {
+        // Left alone when set, as the database tests read it.
+        if std::env::var_os("DATABASE_URL").is_none() {
+            std::env::set_var("DATABASE_URL", "postgres://localhost/rinha");
+        }
+        std::env::set_var("REDIS_URL", "redis://localhost");
+        // No batch settles the consistency tokens of the tests.
+        std::env::set_var("CONSISTENCY_TOKEN_TIMEOUT_MS", "10");
+        Self::new(db, &EnvironmentValues::init()).unwrap().0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn rinha() -> MyRinha {
+        MyRinha::for_tests(PgPool::connect_lazy("postgres://localhost/rinha").unwrap())
+    }
+
+    fn pessoa() -> Pessoa {
+        Pessoa {
+            id: Uuid::now_v7(),
+            apelido: "zé".to_owned(),
+            nome: "José".to_owned(),
+            nascimento: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            stack: None,
+        }
+    }
+
+    fn inserted(pessoa: &Pessoa) -> InsertOutcome {
+        InsertOutcome {
+            inserted: vec![pessoa.clone()],
+            dead_lettered: Vec::new(),
+            settled: true,
+        }
+    }
+
+    fn is_not_found(rinha: &MyRinha, key: &str) -> bool {
+        let not_found = rinha.pessoa_not_found_map.as_ref().unwrap();
+        not_found.get(key).is_some()
+    }
+
+    /// Creates the pessoa and settles its batch, like `create_pessoa` and `batch_insert` do.
+    fn create_and_settle(rinha: &MyRinha, pessoa: &Pessoa) {
+        let key = pessoa.id.to_string();
+        rinha
+            .pessoa_pending_map
+            .insert(key.clone(), serde_json::to_string(pessoa).unwrap());
+        rinha.pessoa_not_found_map.as_ref().unwrap().remove(&key);
+        rinha
+            .pessoa_search_map
+            .invalidate_committed([pessoa.busca().as_str()]);
+        rinha.settle_pending(&[pessoa.id], &inserted(pessoa));
+    }
+
+    #[tokio::test]
+    async fn forgets_a_miss_once_its_pessoa_is_settled() {
+        let rinha = rinha();
+        let pessoa = pessoa();
+        let key = pessoa.id.to_string();
+        rinha.insert_not_found(&key, rinha.pessoa_search_map.epoch());
+        assert!(is_not_found(&rinha, &key));
+        create_and_settle(&rinha, &pessoa);
+        assert!(!is_not_found(&rinha, &key));
+        assert!(rinha.cached_pessoa_by_id(&key).is_some());
+    }
+
+    #[tokio::test]
+    async fn forgets_a_miss_once_its_pessoa_is_replayed() {
+        let rinha = rinha();
+        let pessoa = pessoa();
+        let key = pessoa.id.to_string();
+        rinha.insert_not_found(&key, rinha.pessoa_search_map.epoch());
+        rinha.settle_replayed(&inserted(&pessoa));
+        assert!(!is_not_found(&rinha, &key));
+        assert!(rinha.cached_pessoa_by_id(&key).is_some());
+    }
+
+    #[tokio::test]
+    async fn skips_a_miss_read_before_its_pessoa_was_settled() {
+        let rinha = rinha();
+        let pessoa = pessoa();
+        let key = pessoa.id.to_string();
+        // The query misses the pessoa, which is committed before it caches the miss.
+        let epoch = rinha.pessoa_search_map.epoch();
+        create_and_settle(&rinha, &pessoa);
+        rinha.pessoa_by_id_map.remove(&key);
+        rinha.insert_not_found(&key, epoch);
+        assert!(!is_not_found(&rinha, &key));
+
+        // Or is still pending when it does.
+        let pessoa = self::pessoa();
+        let key = pessoa.id.to_string();
+        let epoch = rinha.pessoa_search_map.epoch();
+        rinha.pessoa_pending_map.insert(key.clone(), String::new());
+        rinha.insert_not_found(&key, epoch);
+        assert!(!is_not_found(&rinha, &key));
+    }
+
+    #[tokio::test]
+    async fn answers_a_token_not_committed_in_time_as_deadline_exceeded() {
+        let rinha = rinha();
+        let seq = rinha.consistency_tokens.queued();
+        let read = rinha
+            .pessoa_by_id(Request::new(PessoaByIdRequest {
+                id: Uuid::now_v7().to_string(),
+                consistency_token: Some(rinha.consistency_tokens.token(seq)),
+            }))
+            .await;
+        assert_eq!(read.unwrap_err().code(), tonic::Code::DeadlineExceeded);
+    }
+}
@@ -1,34 +1,56 @@
-use sqlx::{postgres::PgPoolOptions, PgPool};
+use sqlx::PgPool;
 use tonic::{transport::Server, Request, Response, Status};
 use tonic_tracing_opentelemetry::middleware::server;
 use tower_http::trace::TraceLayer;
+use uuid::Uuid;
 
 use crate::{
-    models::pessoa::Pessoa,
+    migrations,
+    models::pessoa::{pessoa_by_id, search_filtered, search_pessoas, Pessoa, COUNT_PESSOAS},
+    pool::{self, PoolLimit},
+    query_monitor::{query_status, QueryKind, QueryMonitor},
+    read_pools::ReadPools,
     rinha::{
         self,
         rinha_server::{Rinha, RinhaServer},
         CountPessoaReply, CountPessoaRequest, CreatePessoaReply, CreatePessoaRequest,
-        PessoaByIdRequest, PessoaReply, PessoaSearchReply, PessoaSearchRequest,
+        PessoaByIdRequest, PessoaReply, PessoaSearchReply, PessoaSearchRequest, SearchMode,
     },
     utils::{
         env::{EnvironmentValues, LoggerOutput},
-        telemetry,
+        signal, telemetry,
     },
 };
 use std::{sync::Arc, time::Duration};
 
 pub struct MyRinha {
     pub db: PgPool,
+    pub read_pools: ReadPools,
+    pub queries: QueryMonitor,
+    /// Default minimum similarity of the ranked searches.
+    pub similarity_threshold: f32,
 }
 
 impl MyRinha {
     pub async fn from(env_values: &EnvironmentValues) -> Result<Self, Box<dyn std::error::Error>> {
-        let db = PgPoolOptions::new()
-            .max_connections(env_values.db_pool_max_size)
-            .connect(&env_values.database_url)
-            .await?;
-        Ok(Self { db })
+        let db = pool::connect(env_values.database_url.parse()?, &env_values.database_pool).await?;
+        migrations::prepare(&db, env_values).await?;
+        let read_pools = ReadPools::new(
+            db.clone(),
+            &env_values.database_read_replicas,
+            &env_values.database_pool,
+        )?;
+        read_pools.check_capacity(&env_values.database_pool).await?;
+        let pool_limit = Arc::new(PoolLimit::new(
+            &env_values.database_pool,
+            read_pools.named_pools(),
+        ));
+        Ok(Self {
+            db,
+            read_pools,
+            queries: QueryMonitor::new(&env_values.database_queries, pool_limit),
+            similarity_threshold: env_values.pessoa_search_similarity_threshold,
+        })
     }
 }
 
@@ -38,18 +60,14 @@ impl Rinha for MyRinha {
         &self,
         request: Request<PessoaByIdRequest>,
     ) -> Result<Response<PessoaReply>, Status> {
-        let json = sqlx::query_as::<_, Pessoa>(
-            "
-    SELECT * FROM pessoas where id = $1;
-",
-        )
-        .persistent(true)
-        .bind(request.get_ref().id.as_str())
-        .fetch_one(&self.db)
-        .await
-        .map(|pessoa| serde_json::to_string(&pessoa).ok())
-        .ok()
-        .flatten();
+        // Not an id of any pessoa.
+        let Ok(id) = Uuid::parse_str(&request.get_ref().id) else {
+            return Ok(Response::new(PessoaReply { json: None }));
+        };
+        let json = pessoa_by_id(self.read_pools.pool_for_id(&id), id, &self.queries)
+            .await
+            .map_err(|err| query_status(&err))?
+            .map(|pessoa| serde_json::to_string(&pessoa).unwrap());
         Ok(Response::new(PessoaReply { json }))
     }
 
@@ -57,17 +75,23 @@ impl Rinha for MyRinha {
         &self,
         request: Request<PessoaSearchRequest>,
     ) -> Result<Response<PessoaSearchReply>, Status> {
-        let term = request.into_inner().term;
-        let term_param = format!("%{}%", term);
-        let search_res = sqlx::query_as::<sqlx::Postgres, Pessoa>(
-            "
-            SELECT id, apelido, nome, nascimento, stack FROM pessoas p where p.busca_trgm LIKE $1 LIMIT 50;
-        ",
-        )
-        .bind(&term_param)
-        .persistent(true)
-        .fetch_all(&self.db).await.ok().map(|res| serde_json::to_string(&res).ok()).flatten();
-        Ok(Response::new(PessoaSearchReply { json: search_res }))
+        let request = request.into_inner();
+        if request.stack.is_some()
+            || request.desde.is_some()
+            || request.mode() != SearchMode::Substring
+        {
+            let db = self.read_pools.pool();
+            let json = search_filtered(db, &request, self.similarity_threshold, &self.queries)
+                .await
+                .map_err(|err| query_status(&err))?;
+            return Ok(Response::new(PessoaSearchReply { json: Some(json) }));
+        }
+        let search_res = search_pessoas(self.read_pools.pool(), &request.term, &self.queries)
+            .await
+            .map_err(|err| query_status(&err))?;
+        Ok(Response::new(PessoaSearchReply {
+            json: Some(serde_json::to_string(&search_res).unwrap()),
+        }))
     }
 
     async fn create_pessoa(
@@ -76,29 +100,33 @@ impl Rinha for MyRinha {
     ) -> Result<Response<CreatePessoaReply>, Status> {
         let request = request.into_inner();
         if let Some(pessoa) = Pessoa::from(request) {
-            let id = pessoa.id.clone();
+            let id = pessoa.id.to_string();
             let query = sqlx::query::<sqlx::Postgres>(
                     "INSERT INTO pessoas (id, nome, apelido, nascimento, stack) values ($1, $2, $3, $4, $5)"
                 ).bind(pessoa.id)
                 .bind(pessoa.nome)
                 .bind(pessoa.apelido)
                 .bind(pessoa.nascimento)
-                .bind(pessoa.stack.map(|stacks| stacks.join(" ")));
+                .bind(pessoa.stack);
             if query.execute(&self.db).await.is_ok() {
                 Ok(Response::new(CreatePessoaReply {
                     id: Some(id),
                     status: 201,
+                    // Already committed, the reads need not wait for it.
+                    consistency_token: None,
                 }))
             } else {
                 Ok(Response::new(CreatePessoaReply {
                     id: None,
                     status: 422,
+                    consistency_token: None,
                 }))
             }
         } else {
             Ok(Response::new(CreatePessoaReply {
                 id: None,
                 status: 400,
+                consistency_token: None,
             }))
         }
     }
@@ -107,15 +135,20 @@ impl Rinha for MyRinha {
         &self,
         _: Request<CountPessoaRequest>,
     ) -> Result<Response<CountPessoaReply>, Status> {
-        let amount = sqlx::query_as::<_, (i64,)>("SELECT COUNT(id) FROM pessoas;")
-            .fetch_one(&self.db)
-            .await;
-        match amount {
-            Ok(amount) => Ok(Response::new(CountPessoaReply {
-                amount: amount.0 as u64,
-            })),
-            _ => Err(Status::unavailable("Internal server error")),
-        }
+        let query = async {
+            let mut conn = self.queries.acquire(self.read_pools.pool()).await?;
+            sqlx::query_as::<_, (i64,)>(COUNT_PESSOAS)
+                .fetch_one(&mut *conn)
+                .await
+        };
+        let (amount,) = self
+            .queries
+            .run(QueryKind::CountPessoa, COUNT_PESSOAS, None, query)
+            .await
+            .map_err(|err| query_status(&err))?;
+        Ok(Response::new(CountPessoaReply {
+            amount: amount as u64,
+        }))
     }
 }
 
@@ -132,8 +165,12 @@ pub async fn server() -> Result<(), Box<dyn std::error::Error>> {
         .register_encoded_file_descriptor_set(rinha::FILE_DESCRIPTOR_SET)
         .build()?;
     let rinha_svc = MyRinha::from(&env_values).await?;
+    rinha_svc
+        .read_pools
+        .spawn_health_checks(env_values.database_read_replicas.health_check_interval);
     let db_pool = rinha_svc.db.clone();
-    tokio::spawn(async move {
+    let mut health_reporter_on_shutdown = health_reporter.clone();
+    let health_task = tokio::spawn(async move {
         loop {
             tokio::time::sleep(Duration::from_secs(1)).await;
             if db_pool.acquire().await.is_ok() {
@@ -146,6 +183,14 @@ pub async fn server() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
     tracing::info!(message = "Starting server without cache.", %addr);
+    // Stops accepting requests and waits for the ones in flight.
+    let shutdown = async move {
+        signal::shutdown().await;
+        health_task.abort();
+        health_reporter_on_shutdown
+            .set_not_serving::<RinhaServer<MyRinha>>()
+            .await;
+    };
     match env_values.logger {
         Some(LoggerOutput::Otel) => {
             Server::builder()
@@ -153,7 +198,7 @@ pub async fn server() -> Result<(), Box<dyn std::error::Error>> {
                 .add_service(RinhaServer::new(rinha_svc))
                 .add_service(health_service)
                 .add_service(reflection_service)
-                .serve(addr)
+                .serve_with_shutdown(addr, shutdown)
                 .await?
         }
         Some(LoggerOutput::Stdout) => {
@@ -162,7 +207,7 @@ pub async fn server() -> Result<(), Box<dyn std::error::Error>> {
                 .add_service(RinhaServer::new(rinha_svc))
                 .add_service(health_service)
                 .add_service(reflection_service)
-                .serve(addr)
+                .serve_with_shutdown(addr, shutdown)
                 .await?
         }
         None => {
@@ -170,13 +215,13 @@ pub async fn server() -> Result<(), Box<dyn std::error::Error>> {
                 .add_service(health_service)
                 .add_service(reflection_service)
                 .add_service(RinhaServer::new(rinha_svc))
-                .serve(addr)
+                .serve_with_shutdown(addr, shutdown)
                 .await?
         }
     }
-    // Ensure all spans have been shipped.
+    // Ensure all spans and metrics have been shipped.
     if let Some(LoggerOutput::Otel) = env_values.logger {
-        opentelemetry::global::shutdown_tracer_provider();
+        telemetry::shutdown_otel();
     }
     Ok(())
 }
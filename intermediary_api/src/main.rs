@@ -1,6 +1,9 @@
-use rinha_grpc_server::server;
+use rinha_grpc_server::{migrate, server};
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    server().await
+    match std::env::args().nth(1).as_deref() {
+        Some("migrate") => migrate().await,
+        _ => server().await,
+    }
 }
@@ -1,3 +1,4 @@
 cd api
 
-./target/release/$TARGET_NAME
\ No newline at end of file
+# exec so the server is the one receiving the SIGTERM of `docker-compose down`.
+exec ./target/release/$TARGET_NAME